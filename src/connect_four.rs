@@ -0,0 +1,295 @@
+use std::fmt::Display;
+
+use dfdx::tensor::{Tensor1D, TensorCreator};
+
+use crate::{
+    game::{Game, Player, VictoryState},
+    nn::NetworkGame,
+};
+
+pub const COLUMNS: usize = 7;
+pub const ROWS: usize = 6;
+
+/// Implements Connect Four on the classic board with seven columns and six
+/// rows. Stones are dropped into a column and fall down to the lowest free
+/// row.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct ConnectFour {
+    /// Indexed by `[column][row]`, where row 0 is the bottom row.
+    pub board: [[Option<Player>; ROWS]; COLUMNS],
+    pub current_player: Player,
+    /// Cached when a stone completes four in a row, so we don't have to scan
+    /// the whole board on every call to `get_victory_state`.
+    winner: Option<Player>,
+}
+
+/// The column a stone is dropped into.
+type Action = usize;
+
+impl ConnectFour {
+    pub fn new() -> ConnectFour {
+        ConnectFour {
+            board: [[None; ROWS]; COLUMNS],
+            current_player: Player::X,
+            winner: None,
+        }
+    }
+
+    /// Returns the lowest free row of the column, if the column isn't full.
+    fn free_row(&self, column: usize) -> Option<usize> {
+        self.board[column].iter().position(|cell| cell.is_none())
+    }
+
+    fn play(&mut self, player: Player, column: Action) -> bool {
+        let Some(row) = self.free_row(column) else {
+            return false;
+        };
+
+        self.board[column][row] = Some(player);
+        if self.completes_four(column, row) {
+            self.winner = Some(player);
+        }
+        true
+    }
+
+    /// Checks whether the stone at (column, row) is part of four in a row.
+    fn completes_four(&self, column: usize, row: usize) -> bool {
+        let player = self.board[column][row];
+        let directions = [(1, 0), (0, 1), (1, 1), (1, -1)];
+
+        directions.iter().any(|&(dx, dy)| {
+            let count = 1
+                + self.count_in_direction(column, row, dx, dy, player)
+                + self.count_in_direction(column, row, -dx, -dy, player);
+            count >= 4
+        })
+    }
+
+    /// Counts the stones of `player` next to (column, row) in the given
+    /// direction, not including (column, row) itself.
+    fn count_in_direction(
+        &self,
+        column: usize,
+        row: usize,
+        dx: isize,
+        dy: isize,
+        player: Option<Player>,
+    ) -> usize {
+        let mut count = 0;
+        let mut x = column as isize + dx;
+        let mut y = row as isize + dy;
+
+        while (0..COLUMNS as isize).contains(&x)
+            && (0..ROWS as isize).contains(&y)
+            && self.board[x as usize][y as usize] == player
+        {
+            count += 1;
+            x += dx;
+            y += dy;
+        }
+
+        count
+    }
+}
+
+impl Default for ConnectFour {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for ConnectFour {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for row in (0..ROWS).rev() {
+            for column in 0..COLUMNS {
+                match self.board[column][row] {
+                    Some(Player::X) => write!(f, "X")?,
+                    Some(Player::O) => write!(f, "O")?,
+                    None => write!(f, "•")?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Game for ConnectFour {
+    type Action = Action;
+
+    fn get_actions(&self) -> Vec<Self::Action> {
+        if self.winner.is_some() {
+            return Vec::new();
+        }
+
+        (0..COLUMNS)
+            .filter(|&column| self.free_row(column).is_some())
+            .collect()
+    }
+
+    fn apply_action(&mut self, action: Self::Action) {
+        self.play(self.get_player(), action);
+        self.current_player = !self.current_player;
+    }
+
+    fn get_victory_state(&self) -> VictoryState {
+        if let Some(winner) = self.winner {
+            VictoryState::Won(winner)
+        } else if self.get_actions().is_empty() {
+            VictoryState::Draw
+        } else {
+            VictoryState::InProgress
+        }
+    }
+
+    fn get_player(&self) -> Player {
+        self.current_player
+    }
+}
+
+impl NetworkGame<42, 7> for ConnectFour {
+    const NAME: &'static str = "connect_four";
+
+    fn tensorize(&self) -> Tensor1D<42> {
+        let mut data = [0.0; COLUMNS * ROWS];
+
+        for column in 0..COLUMNS {
+            for row in 0..ROWS {
+                let value = match self.board[column][row] {
+                    None => 0.0,
+                    Some(Player::X) => 1.0,
+                    Some(Player::O) => -1.0,
+                };
+                data[column + row * COLUMNS] = value;
+            }
+        }
+
+        Tensor1D::new(data)
+    }
+
+    fn action_to_index(action: Action) -> usize {
+        action
+    }
+
+    fn index_to_action(index: usize) -> Action {
+        index
+    }
+
+    /// The board is symmetric under mirroring the columns.
+    fn symmetries(&self) -> Vec<(Self, [usize; 7])> {
+        let mut mirrored = self.clone();
+        mirrored.board.reverse();
+
+        let mut permutation = [0; COLUMNS];
+        for (column, target) in permutation.iter_mut().enumerate() {
+            *target = COLUMNS - 1 - column;
+        }
+
+        vec![(mirrored, permutation)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Drops stones of one player into the given columns, without changing
+    /// the player to move.
+    fn drop_stones(state: &mut ConnectFour, player: Player, columns: &[usize]) {
+        for &column in columns {
+            assert!(state.play(player, column));
+        }
+    }
+
+    fn assert_won_by_x(state: &ConnectFour) {
+        assert!(
+            matches!(state.get_victory_state(), VictoryState::Won(Player::X)),
+            "in\n{}",
+            state
+        );
+        assert!(state.get_actions().is_empty());
+    }
+
+    #[test]
+    fn vertical_four_wins() {
+        let mut state = ConnectFour::new();
+        drop_stones(&mut state, Player::X, &[2, 2, 2]);
+        assert!(!state.get_victory_state().is_terminal());
+        drop_stones(&mut state, Player::X, &[2]);
+        assert_won_by_x(&state);
+    }
+
+    #[test]
+    fn horizontal_four_wins() {
+        let mut state = ConnectFour::new();
+        // The last stone fills the gap in the middle.
+        drop_stones(&mut state, Player::X, &[3, 4, 6]);
+        assert!(!state.get_victory_state().is_terminal());
+        drop_stones(&mut state, Player::X, &[5]);
+        assert_won_by_x(&state);
+    }
+
+    #[test]
+    fn rising_diagonal_wins() {
+        let mut state = ConnectFour::new();
+        drop_stones(&mut state, Player::O, &[1, 2, 2, 3, 3, 3]);
+        drop_stones(&mut state, Player::X, &[0, 1, 2]);
+        assert!(!state.get_victory_state().is_terminal());
+        drop_stones(&mut state, Player::X, &[3]);
+        assert_won_by_x(&state);
+    }
+
+    #[test]
+    fn falling_diagonal_wins() {
+        let mut state = ConnectFour::new();
+        drop_stones(&mut state, Player::O, &[0, 0, 0, 1, 1, 2]);
+        drop_stones(&mut state, Player::X, &[0, 1, 3]);
+        assert!(!state.get_victory_state().is_terminal());
+        drop_stones(&mut state, Player::X, &[2]);
+        assert_won_by_x(&state);
+    }
+
+    #[test]
+    fn stones_fall_down_and_full_columns_are_not_playable() {
+        let mut state = ConnectFour::new();
+        for _ in 0..ROWS {
+            state.apply_action(4);
+        }
+
+        assert_eq!(state.board[4][0], Some(Player::X));
+        assert_eq!(state.board[4][ROWS - 1], Some(Player::O));
+        assert_eq!(state.get_actions(), vec![0, 1, 2, 3, 5, 6]);
+        assert!(!state.play(Player::X, 4));
+    }
+
+    #[test]
+    fn full_board_without_four_is_a_draw() {
+        let mut state = ConnectFour::new();
+        for column in 0..COLUMNS {
+            for row in 0..ROWS {
+                // Alternates the rows and flips the pattern every two
+                // columns, which never makes four in a row.
+                let player = if (row % 2 == 1) ^ ((column / 2) % 2 == 1) {
+                    Player::O
+                } else {
+                    Player::X
+                };
+                drop_stones(&mut state, player, &[column]);
+            }
+        }
+
+        assert!(state.get_actions().is_empty());
+        assert!(matches!(state.get_victory_state(), VictoryState::Draw));
+    }
+
+    #[test]
+    fn mirror_image_maps_legal_actions() {
+        let positions = crate::nn::tests::assert_symmetric_actions(ConnectFour::new());
+        // A won position stays won by the same player.
+        for state in positions {
+            for (variant, _) in state.symmetries() {
+                assert_eq!(variant.winner, state.winner);
+            }
+        }
+    }
+}
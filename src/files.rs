@@ -1,90 +1,967 @@
-use std::{fs::File, io, mem};
+//! Import from MessagePack and export to MessagePack
 
-use dfdx::tensor::HasArrayData;
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+};
+
+use dfdx::{
+    prelude::{Linear, SgdConfig},
+    tensor::{HasArrayData, Tensor1D, TensorCreator},
+};
 use serde::{Deserialize, Serialize};
-use serde_big_array::BigArray;
 
-use crate::nn::MultiLayerPerceptron;
+use crate::{
+    learning::{ReplayBuffer, SamplingStrategy, Trainer, TrainingDataset, TrainingDatum},
+    nn::{architecture, LayerDescription, MultiLayerPerceptron, NetworkGame},
+};
+
+/// Everything that can go wrong when reading or writing model and dataset
+/// files.
+#[derive(Debug)]
+pub enum FileError {
+    Io(io::Error),
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+    /// The file has no format version at all, like models in the format of
+    /// [load_legacy_model].
+    MissingFormatVersion,
+    /// The file was written with another [MODEL_FORMAT_VERSION].
+    VersionMismatch {
+        expected: u32,
+        found: u32,
+    },
+    /// The model was trained for another game.
+    GameMismatch {
+        expected: String,
+        found: String,
+    },
+    /// The layers in the file don't match the network architecture.
+    ArchitectureMismatch {
+        found: Vec<LayerDescription>,
+    },
+    /// A tensor in the file has the wrong number of values.
+    ShapeMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// The velocity of an optimizer with momentum is private to dfdx, so a
+    /// training run that uses it can't be checkpointed.
+    UnsupportedMomentum,
+    /// The replay buffer in a checkpoint can't hold a single generation.
+    ZeroReplayCapacity,
+}
+
+impl Display for FileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FileError::Io(error) => write!(f, "I/O error: {}", error),
+            FileError::Encode(error) => write!(f, "Failed to encode: {}", error),
+            FileError::Decode(error) => write!(f, "Failed to decode: {}", error),
+            FileError::VersionMismatch { expected, found } => write!(
+                f,
+                "Model file has format version {}, expected {}",
+                found, expected
+            ),
+            FileError::MissingFormatVersion => {
+                write!(f, "File has no format version, it may be a legacy model")
+            }
+            FileError::GameMismatch { expected, found } => {
+                write!(f, "Model is for {}, not for {}", found, expected)
+            }
+            FileError::ArchitectureMismatch { found } => {
+                write!(f, "Model has an unexpected architecture: {:?}", found)
+            }
+            FileError::ShapeMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Expected {} values for {}, got {}",
+                expected, name, found
+            ),
+            FileError::UnsupportedMomentum => {
+                write!(f, "Can't checkpoint an optimizer with momentum")
+            }
+            FileError::ZeroReplayCapacity => {
+                write!(f, "Checkpoint has a replay buffer without capacity")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FileError::Io(error) => Some(error),
+            FileError::Encode(error) => Some(error),
+            FileError::Decode(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FileError {
+    fn from(error: io::Error) -> Self {
+        FileError::Io(error)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for FileError {
+    fn from(error: rmp_serde::encode::Error) -> Self {
+        FileError::Encode(error)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for FileError {
+    fn from(error: rmp_serde::decode::Error) -> Self {
+        FileError::Decode(error)
+    }
+}
+
+/// Version of the model file format written by [save_model]. Files with a
+/// different version are rejected by [load_model].
+pub const MODEL_FORMAT_VERSION: u32 = 1;
+
+/// Version of the checkpoint format written by [save_checkpoint].
+pub const CHECKPOINT_FORMAT_VERSION: u32 = 1;
+
+/// Just the version of a [ModelFile] or a [CheckpointFile], so it can be
+/// checked before the rest of the file is decoded.
+#[derive(Debug, Deserialize)]
+struct FormatHeader {
+    format_version: Option<u32>,
+}
 
-/// Import from MessagePack and export to MessagePack
+#[derive(Debug, Deserialize, Serialize)]
+struct ModelFile {
+    format_version: u32,
+    game: String,
+    layers: Vec<LayerRecord>,
+}
 
 #[derive(Debug, Deserialize, Serialize)]
-struct IntermediaryModel {
-    #[serde(with = "BigArray")]
-    l1w: [f32; 9 * 128],
-    #[serde(with = "BigArray")]
-    l1b: [f32; 128],
-    #[serde(with = "BigArray")]
-    l2w: [f32; 128 * 128],
-    #[serde(with = "BigArray")]
-    l2b: [f32; 128],
-    #[serde(with = "BigArray")]
-    l3aw: [f32; 128 * 9],
-    #[serde(with = "BigArray")]
-    l3ab: [f32; 9],
-    #[serde(with = "BigArray")]
-    l3bw: [f32; 128 * 1],
-    #[serde(with = "BigArray")]
-    l3bb: [f32; 1],
-}
-
-pub fn save_model(mlp: MultiLayerPerceptron, filename: &str) -> Result<(), io::Error> {
+struct LayerRecord {
+    description: LayerDescription,
+    /// Row major with shape (output, input).
+    weight: Vec<f32>,
+    bias: Vec<f32>,
+}
+
+/// Flattens the weight of a linear layer in row major order with shape
+/// (output, input), followed by its bias.
+fn flatten<const I: usize, const O: usize>(linear: &Linear<I, O>) -> (Vec<f32>, Vec<f32>) {
+    let weight = linear.weight.data().iter().flatten().copied().collect();
+    let bias = linear.bias.data().to_vec();
+    (weight, bias)
+}
+
+/// The inverse of [flatten]. Fails without touching the layer if the
+/// number of values doesn't match its shape.
+fn unflatten<const I: usize, const O: usize>(
+    name: &str,
+    weight: &[f32],
+    bias: &[f32],
+    linear: &mut Linear<I, O>,
+) -> Result<(), FileError> {
+    if weight.len() != I * O {
+        return Err(FileError::ShapeMismatch {
+            name: format!("{} weight", name),
+            expected: I * O,
+            found: weight.len(),
+        });
+    }
+    if bias.len() != O {
+        return Err(FileError::ShapeMismatch {
+            name: format!("{} bias", name),
+            expected: O,
+            found: bias.len(),
+        });
+    }
+
+    for (row, values) in linear.weight.mut_data().iter_mut().zip(weight.chunks(I)) {
+        row.copy_from_slice(values);
+    }
+    linear.bias.mut_data().copy_from_slice(bias);
+
+    Ok(())
+}
+
+/// Like [flatten], but the weight is in column major order, i.e. all
+/// weights of the first input come first.
+fn flatten_column_major<const I: usize, const O: usize>(
+    linear: &Linear<I, O>,
+) -> (Vec<f32>, Vec<f32>) {
+    let weight = linear.weight.data();
+    let weight = (0..I)
+        .flat_map(|i| (0..O).map(move |o| weight[o][i]))
+        .collect();
+    let bias = linear.bias.data().to_vec();
+    (weight, bias)
+}
+
+/// The inverse of [flatten_column_major].
+fn unflatten_column_major<const I: usize, const O: usize>(
+    name: &str,
+    weight: &[f32],
+    bias: &[f32],
+    linear: &mut Linear<I, O>,
+) -> Result<(), FileError> {
+    if weight.len() != I * O {
+        return Err(FileError::ShapeMismatch {
+            name: format!("{} weight", name),
+            expected: I * O,
+            found: weight.len(),
+        });
+    }
+
+    let weight: Vec<f32> = (0..O)
+        .flat_map(|o| (0..I).map(move |i| weight[i * O + o]))
+        .collect();
+    unflatten(name, &weight, bias, linear)
+}
+
+fn layer_record<const I: usize, const O: usize>(
+    description: LayerDescription,
+    linear: &Linear<I, O>,
+) -> LayerRecord {
+    let (weight, bias) = flatten(linear);
+    LayerRecord {
+        description,
+        weight,
+        bias,
+    }
+}
+
+fn load_layer_record<const I: usize, const O: usize>(
+    record: &LayerRecord,
+    linear: &mut Linear<I, O>,
+) -> Result<(), FileError> {
+    unflatten(
+        &record.description.name,
+        &record.weight,
+        &record.bias,
+        linear,
+    )
+}
+
+fn model_file<G: NetworkGame<I, A>, const I: usize, const A: usize>(
+    mlp: &MultiLayerPerceptron<I, A>,
+) -> ModelFile {
     let (l1, _, l2, _, l3) = mlp;
-    let ((l3a, _), (l3b, _)) = l3.0;
-
-    let im = unsafe {
-        IntermediaryModel {
-            l1w: mem::transmute(*l1.weight.data()),
-            l1b: *l1.bias.data(),
-            l2w: mem::transmute(*l2.weight.data()),
-            l2b: *l2.bias.data(),
-            l3aw: mem::transmute(*l3a.weight.data()),
-            l3ab: *l3a.bias.data(),
-            l3bw: mem::transmute(*l3b.weight.data()),
-            l3bb: *l3b.bias.data(),
-        }
-    };
+    let ((l3a, _), (l3b, _)) = &l3.0;
+    let [d1, d2, d3a, d3b]: [LayerDescription; 4] = architecture::<I, A>()
+        .try_into()
+        .expect("The perceptron has four linear layers");
+
+    ModelFile {
+        format_version: MODEL_FORMAT_VERSION,
+        game: G::NAME.to_string(),
+        layers: vec![
+            layer_record(d1, l1),
+            layer_record(d2, l2),
+            layer_record(d3a, l3a),
+            layer_record(d3b, l3b),
+        ],
+    }
+}
+
+fn model_from_file<G: NetworkGame<I, A>, const I: usize, const A: usize>(
+    model: ModelFile,
+) -> Result<MultiLayerPerceptron<I, A>, FileError> {
+    if model.format_version != MODEL_FORMAT_VERSION {
+        return Err(FileError::VersionMismatch {
+            expected: MODEL_FORMAT_VERSION,
+            found: model.format_version,
+        });
+    }
+    if model.game != G::NAME {
+        return Err(FileError::GameMismatch {
+            expected: G::NAME.to_string(),
+            found: model.game,
+        });
+    }
+
+    let descriptions: Vec<LayerDescription> = model
+        .layers
+        .iter()
+        .map(|layer| layer.description.clone())
+        .collect();
+    if descriptions != architecture::<I, A>() {
+        return Err(FileError::ArchitectureMismatch {
+            found: descriptions,
+        });
+    }
 
-    // Serialize the IntermediaryModel to a file.
+    let mut mlp: MultiLayerPerceptron<I, A> = Default::default();
+    load_layer_record(&model.layers[0], &mut mlp.0)?;
+    load_layer_record(&model.layers[1], &mut mlp.2)?;
+    load_layer_record(&model.layers[2], &mut mlp.4 .0 .0 .0)?;
+    load_layer_record(&model.layers[3], &mut mlp.4 .0 .1 .0)?;
+
+    Ok(mlp)
+}
+
+/// Checks the version before the rest of the file is decoded, so an old
+/// file gives a [FileError::VersionMismatch] or a
+/// [FileError::MissingFormatVersion] instead of a decode error.
+fn check_format_version(bytes: &[u8], expected: u32) -> Result<(), FileError> {
+    let header: FormatHeader = rmp_serde::from_slice(bytes)?;
+    match header.format_version {
+        None => Err(FileError::MissingFormatVersion),
+        Some(found) if found != expected => Err(FileError::VersionMismatch { expected, found }),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Saves the model together with a description of the game and the
+/// network architecture.
+pub fn save_model<G: NetworkGame<I, A>, const I: usize, const A: usize>(
+    mlp: &MultiLayerPerceptron<I, A>,
+    filename: &str,
+) -> Result<(), FileError> {
+    let model = model_file::<G, I, A>(mlp);
     let mut file = File::create(filename)?;
+    rmp_serde::encode::write_named(&mut file, &model)?;
+    Ok(())
+}
 
-    rmp_serde::encode::write_named(&mut file, &im).unwrap();
+/// Loads a model saved by [save_model]. Fails if the file was written with
+/// another format version, for another game or for another architecture.
+/// A model without a header, like `thomas.mp`, gives a
+/// [FileError::MissingFormatVersion] and can be read with
+/// [load_legacy_model].
+pub fn load_model<G: NetworkGame<I, A>, const I: usize, const A: usize>(
+    filename: &str,
+) -> Result<MultiLayerPerceptron<I, A>, FileError> {
+    let bytes = std::fs::read(filename)?;
+    check_format_version(&bytes, MODEL_FORMAT_VERSION)?;
+    let model: ModelFile = rmp_serde::from_slice(&bytes)?;
+    model_from_file::<G, I, A>(model)
+}
+
+/// The model format `thomas.mp` was written in, which predates
+/// [MODEL_FORMAT_VERSION] and has no header at all.
+///
+/// The file is a MessagePack map from the names below to flat `f32` arrays,
+/// so the order of the keys doesn't matter. It only stores parameters:
+/// layer types and activations are not part of the file, so it can only
+/// hold the four dense layers of a TicTacToe [MultiLayerPerceptron]:
+///
+/// - `l1`: 9 -> 128, ReLU
+/// - `l2`: 128 -> 128, ReLU
+/// - `l3a`: 128 -> 9, Softmax (policy head)
+/// - `l3b`: 128 -> 1, Tanh (value head)
+///
+/// Each `w` array is a (output, input) matrix in column major order, as
+/// Julia stores it, i.e. all weights of the first input come first. Read
+/// this way, the Thomas network plays the winning and blocking moves of
+/// TicTacToe, while in row major order its policy is close to uniform.
+/// Each `b` array holds one bias per output neuron.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct LegacyModel {
+    l1w: Vec<f32>,
+    l1b: Vec<f32>,
+    l2w: Vec<f32>,
+    l2b: Vec<f32>,
+    l3aw: Vec<f32>,
+    l3ab: Vec<f32>,
+    l3bw: Vec<f32>,
+    l3bb: Vec<f32>,
+}
+
+/// Writes the model in the legacy format, see [LegacyModel].
+pub fn save_legacy_model(
+    mlp: &MultiLayerPerceptron<9, 9>,
+    filename: &str,
+) -> Result<(), FileError> {
+    let (l1, _, l2, _, l3) = mlp;
+    let ((l3a, _), (l3b, _)) = &l3.0;
+
+    let (l1w, l1b) = flatten_column_major(l1);
+    let (l2w, l2b) = flatten_column_major(l2);
+    let (l3aw, l3ab) = flatten_column_major(l3a);
+    let (l3bw, l3bb) = flatten_column_major(l3b);
+    let model = LegacyModel {
+        l1w,
+        l1b,
+        l2w,
+        l2b,
+        l3aw,
+        l3ab,
+        l3bw,
+        l3bb,
+    };
+
+    let mut file = File::create(filename)?;
+    rmp_serde::encode::write_named(&mut file, &model)?;
 
     Ok(())
 }
 
-pub fn load_model(filename: &str) -> Result<MultiLayerPerceptron, io::Error> {
-    // Deserialize the IntermediaryModel from a file.
+/// Reads a model in the legacy format, see [LegacyModel].
+pub fn load_legacy_model(filename: &str) -> Result<MultiLayerPerceptron<9, 9>, FileError> {
     let file = File::open(filename)?;
-    let im: IntermediaryModel = rmp_serde::decode::from_read(file).unwrap();
+    let model: LegacyModel = rmp_serde::decode::from_read(file)?;
 
-    let mut mlp: MultiLayerPerceptron = Default::default();
+    let mut mlp: MultiLayerPerceptron<9, 9> = Default::default();
+    unflatten_column_major("l1", &model.l1w, &model.l1b, &mut mlp.0)?;
+    unflatten_column_major("l2", &model.l2w, &model.l2b, &mut mlp.2)?;
+    unflatten_column_major("l3a", &model.l3aw, &model.l3ab, &mut mlp.4 .0 .0 .0)?;
+    unflatten_column_major("l3b", &model.l3bw, &model.l3bb, &mut mlp.4 .0 .1 .0)?;
 
-    // Un-flatten all the Tensors from one dimensional vectors.
-    let l1w: [[f32; 9]; 128] = unsafe { mem::transmute(im.l1w) };
-    let l1b: [f32; 128] = im.l1b;
+    Ok(mlp)
+}
+
+/// A [TrainingDatum] as it is stored on disk. A dataset file is a plain
+/// sequence of these, so it can be appended to and read one by one.
+#[derive(Debug, Deserialize, Serialize)]
+struct StoredDatum {
+    input: Vec<f32>,
+    improved_policy: Vec<f32>,
+    expected_value: f32,
+}
 
-    let l2w: [[f32; 128]; 128] = unsafe { mem::transmute(im.l2w) };
-    let l2b: [f32; 128] = im.l2b;
+impl StoredDatum {
+    fn new<const I: usize, const A: usize>(datum: &TrainingDatum<I, A>) -> Self {
+        StoredDatum {
+            input: datum.input.data().to_vec(),
+            improved_policy: datum.improved_policy.data().to_vec(),
+            expected_value: datum.expected_value,
+        }
+    }
 
-    let l3aw: [[f32; 128]; 9] = unsafe { mem::transmute(im.l3aw) };
-    let l3ab: [f32; 9] = im.l3ab;
+    fn into_datum<const I: usize, const A: usize>(self) -> Result<TrainingDatum<I, A>, FileError> {
+        let input: [f32; I] =
+            self.input
+                .try_into()
+                .map_err(|input: Vec<f32>| FileError::ShapeMismatch {
+                    name: "input".to_string(),
+                    expected: I,
+                    found: input.len(),
+                })?;
+        let improved_policy: [f32; A] =
+            self.improved_policy
+                .try_into()
+                .map_err(|policy: Vec<f32>| FileError::ShapeMismatch {
+                    name: "improved policy".to_string(),
+                    expected: A,
+                    found: policy.len(),
+                })?;
 
-    let l3bw: [[f32; 128]; 1] = unsafe { mem::transmute(im.l3bw) };
-    let l3bb: [f32; 1] = im.l3bb;
+        Ok(TrainingDatum {
+            input: Tensor1D::new(input),
+            improved_policy: Tensor1D::new(improved_policy),
+            expected_value: self.expected_value,
+        })
+    }
+}
 
-    // Load the Tensors into the MultiLayerPerceptron.
-    mlp.0.weight.mut_data().copy_from_slice(&l1w);
-    mlp.0.bias.mut_data().copy_from_slice(&l1b);
+/// Writes training data to a MessagePack file one datum at a time.
+pub struct DatasetWriter {
+    writer: BufWriter<File>,
+}
 
-    mlp.2.weight.mut_data().copy_from_slice(&l2w);
-    mlp.2.bias.mut_data().copy_from_slice(&l2b);
+impl DatasetWriter {
+    /// Creates the file, replacing an existing one.
+    pub fn create(filename: &str) -> Result<DatasetWriter, FileError> {
+        let file = File::create(filename)?;
+        Ok(DatasetWriter {
+            writer: BufWriter::new(file),
+        })
+    }
 
-    mlp.4 .0 .0 .0.weight.mut_data().copy_from_slice(&l3aw);
-    mlp.4 .0 .0 .0.bias.mut_data().copy_from_slice(&l3ab);
+    /// Opens the file for appending, creating it if it doesn't exist.
+    pub fn append(filename: &str) -> Result<DatasetWriter, FileError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(filename)?;
+        Ok(DatasetWriter {
+            writer: BufWriter::new(file),
+        })
+    }
 
-    mlp.4 .0 .1 .0.weight.mut_data().copy_from_slice(&l3bw);
-    mlp.4 .0 .1 .0.bias.mut_data().copy_from_slice(&l3bb);
+    pub fn write<const I: usize, const A: usize>(
+        &mut self,
+        datum: &TrainingDatum<I, A>,
+    ) -> Result<(), FileError> {
+        rmp_serde::encode::write_named(&mut self.writer, &StoredDatum::new(datum))?;
+        Ok(())
+    }
 
-    Ok(mlp)
+    pub fn write_dataset<const I: usize, const A: usize>(
+        &mut self,
+        dataset: &TrainingDataset<I, A>,
+    ) -> Result<(), FileError> {
+        for datum in dataset.data.iter() {
+            self.write(datum)?;
+        }
+        Ok(())
+    }
+
+    /// Data is buffered, so this must be called before the writer is
+    /// dropped to see errors of the last write.
+    pub fn flush(&mut self) -> Result<(), FileError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads training data from a MessagePack file one datum at a time, without
+/// loading the whole file into memory.
+///
+/// A partial datum at the end of the file, as left behind by a writer that
+/// crashed in the middle of a write, ends the stream like the end of the
+/// file, so the complete data before it can still be used. A datum that is
+/// complete but malformed is still an error.
+pub struct DatasetReader<const I: usize, const A: usize> {
+    reader: BufReader<File>,
+}
+
+impl<const I: usize, const A: usize> DatasetReader<I, A> {
+    pub fn open(filename: &str) -> Result<Self, FileError> {
+        let file = File::open(filename)?;
+        Ok(DatasetReader {
+            reader: BufReader::new(file),
+        })
+    }
+
+    fn read_datum(&mut self) -> Result<TrainingDatum<I, A>, FileError> {
+        let stored: StoredDatum = rmp_serde::decode::from_read(&mut self.reader)?;
+        stored.into_datum()
+    }
+}
+
+impl<const I: usize, const A: usize> Iterator for DatasetReader<I, A> {
+    type Item = Result<TrainingDatum<I, A>, FileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // The file ends exactly between two data.
+        match self.reader.fill_buf() {
+            Ok([]) => None,
+            Ok(_) => match self.read_datum() {
+                Err(FileError::Decode(error)) if is_truncated(&error) => None,
+                result => Some(result),
+            },
+            Err(error) => Some(Err(error.into())),
+        }
+    }
+}
+
+/// Whether decoding failed because the file ended in the middle of a datum.
+fn is_truncated(error: &rmp_serde::decode::Error) -> bool {
+    match error {
+        rmp_serde::decode::Error::InvalidMarkerRead(error)
+        | rmp_serde::decode::Error::InvalidDataRead(error) => {
+            error.kind() == io::ErrorKind::UnexpectedEof
+        }
+        _ => false,
+    }
+}
+
+pub fn save_dataset<const I: usize, const A: usize>(
+    dataset: &TrainingDataset<I, A>,
+    filename: &str,
+) -> Result<(), FileError> {
+    let mut writer = DatasetWriter::create(filename)?;
+    writer.write_dataset(dataset)?;
+    writer.flush()
+}
+
+/// Loads all data of a dataset file. A partial datum at the end of the file
+/// is dropped, see [DatasetReader].
+pub fn load_dataset<const I: usize, const A: usize>(
+    filename: &str,
+) -> Result<TrainingDataset<I, A>, FileError> {
+    let data = DatasetReader::open(filename)?.collect::<Result<_, _>>()?;
+    Ok(TrainingDataset { data })
+}
+
+/// The state of a [Trainer] that changes during training. Hyperparameters
+/// like the search config, the loss config and the number of epochs come
+/// from the code that resumes the run.
+#[derive(Debug, Deserialize, Serialize)]
+struct CheckpointFile {
+    format_version: u32,
+    model: ModelFile,
+    learning_rate: f32,
+    generation: usize,
+    seed: u64,
+    replay_capacity: usize,
+    sampling: SamplingStrategy,
+    /// Newest generation first.
+    replay_generations: Vec<Vec<StoredDatum>>,
+}
+
+/// Saves everything that is needed to resume the training run exactly: the
+/// model, the learning rate, the generation counter, the seed and the replay
+/// buffer. Fails with [FileError::UnsupportedMomentum] if the optimizer uses
+/// momentum, because its velocity can't be saved.
+///
+/// The checkpoint is written to a temporary file first, so a crash while
+/// saving doesn't destroy the previous checkpoint.
+pub fn save_checkpoint<G: NetworkGame<I, A>, const I: usize, const A: usize>(
+    trainer: &Trainer<G, I, A>,
+    filename: &str,
+) -> Result<(), FileError> {
+    if trainer.sgd.cfg.momentum.is_some() {
+        return Err(FileError::UnsupportedMomentum);
+    }
+    let replay_generations = trainer
+        .replay_buffer
+        .generations()
+        .map(|generation| generation.iter().map(StoredDatum::new).collect())
+        .collect();
+
+    let checkpoint = CheckpointFile {
+        format_version: CHECKPOINT_FORMAT_VERSION,
+        model: model_file::<G, I, A>(&trainer.config.mlp),
+        learning_rate: trainer.sgd.cfg.lr,
+        generation: trainer.generation,
+        seed: trainer.seed,
+        replay_capacity: trainer.replay_buffer.capacity(),
+        sampling: trainer.replay_buffer.sampling,
+        replay_generations,
+    };
+
+    let temporary = format!("{}.tmp", filename);
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    rmp_serde::encode::write_named(&mut writer, &checkpoint)?;
+    let file = writer.into_inner().map_err(|error| error.into_error())?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, filename)?;
+    Ok(())
+}
+
+/// Restores a checkpoint saved by [save_checkpoint] into a trainer that was
+/// set up like the one that was saved.
+pub fn load_checkpoint<G: NetworkGame<I, A>, const I: usize, const A: usize>(
+    trainer: Trainer<G, I, A>,
+    filename: &str,
+) -> Result<Trainer<G, I, A>, FileError> {
+    let bytes = std::fs::read(filename)?;
+    check_format_version(&bytes, CHECKPOINT_FORMAT_VERSION)?;
+    let checkpoint: CheckpointFile = rmp_serde::from_slice(&bytes)?;
+
+    let mlp = model_from_file::<G, I, A>(checkpoint.model)?;
+
+    if checkpoint.replay_capacity == 0 {
+        return Err(FileError::ZeroReplayCapacity);
+    }
+    let mut replay_buffer =
+        ReplayBuffer::new(checkpoint.replay_capacity).with_sampling(checkpoint.sampling);
+    // Push the oldest generation first, so the order is preserved.
+    for generation in checkpoint.replay_generations.into_iter().rev() {
+        let data = generation
+            .into_iter()
+            .map(StoredDatum::into_datum)
+            .collect::<Result<_, _>>()?;
+        replay_buffer.push_generation(TrainingDataset { data });
+    }
+
+    let mut trainer = trainer
+        .with_sgd_config(SgdConfig {
+            lr: checkpoint.learning_rate,
+            momentum: None,
+        })
+        .with_replay_buffer(replay_buffer)
+        .with_seed(checkpoint.seed);
+    trainer.generation = checkpoint.generation;
+    trainer.config.mlp = mlp;
+
+    Ok(trainer)
+}
+
+#[cfg(test)]
+mod tests {
+    use dfdx::nn::Module;
+
+    use super::*;
+    use crate::{game::Game, meta_tac::MetaTac, nn::NetworkMctsConfig, tictactoe::TicTacToe};
+
+    const THOMAS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/thomas.mp");
+
+    fn temp_file(name: &str) -> String {
+        let filename = std::env::temp_dir().join(name);
+        filename.to_str().unwrap().to_string()
+    }
+
+    fn dataset(len: usize, offset: f32) -> TrainingDataset<9, 9> {
+        let data = (0..len)
+            .map(|i| {
+                let value = offset + i as f32;
+                TrainingDatum {
+                    input: Tensor1D::new([value; 9]),
+                    improved_policy: Tensor1D::new([value / 10.0; 9]),
+                    expected_value: -value,
+                }
+            })
+            .collect();
+        TrainingDataset { data }
+    }
+
+    fn assert_same_data(actual: &[TrainingDatum<9, 9>], expected: &[TrainingDatum<9, 9>]) {
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert_eq!(actual.input.data(), expected.input.data());
+            assert_eq!(
+                actual.improved_policy.data(),
+                expected.improved_policy.data()
+            );
+            assert_eq!(actual.expected_value, expected.expected_value);
+        }
+    }
+
+    #[test]
+    fn dataset_round_trip() {
+        let filename = temp_file("rtac_dataset_round_trip.mp");
+        let dataset = dataset(5, 0.0);
+        save_dataset(&dataset, &filename).unwrap();
+
+        let loaded = load_dataset::<9, 9>(&filename).unwrap();
+        assert_same_data(&loaded.data, &dataset.data);
+
+        // An empty file is an empty dataset.
+        save_dataset(&TrainingDataset::<9, 9> { data: vec![] }, &filename).unwrap();
+        assert!(load_dataset::<9, 9>(&filename).unwrap().data.is_empty());
+    }
+
+    #[test]
+    fn appended_data_follows_the_existing_data() {
+        let filename = temp_file("rtac_dataset_append.mp");
+        let first = dataset(3, 0.0);
+        let second = dataset(2, 100.0);
+        save_dataset(&first, &filename).unwrap();
+
+        let mut writer = DatasetWriter::append(&filename).unwrap();
+        writer.write_dataset(&second).unwrap();
+        writer.flush().unwrap();
+
+        let loaded = load_dataset::<9, 9>(&filename).unwrap();
+        let expected: Vec<_> = first.data.into_iter().chain(second.data).collect();
+        assert_same_data(&loaded.data, &expected);
+    }
+
+    #[test]
+    fn partial_datum_at_the_end_is_dropped() {
+        let filename = temp_file("rtac_dataset_truncated.mp");
+        let dataset = dataset(3, 0.0);
+        save_dataset(&dataset, &filename).unwrap();
+        let bytes = std::fs::read(&filename).unwrap();
+        let last_datum = {
+            let mut last = Vec::new();
+            rmp_serde::encode::write_named(&mut last, &StoredDatum::new(&dataset.data[2])).unwrap();
+            last.len()
+        };
+
+        // Cut the file at every byte of the last datum.
+        for cut in 1..last_datum {
+            std::fs::write(&filename, &bytes[..bytes.len() - cut]).unwrap();
+            let loaded = load_dataset::<9, 9>(&filename).unwrap();
+            assert_same_data(&loaded.data, &dataset.data[..2]);
+        }
+    }
+
+    #[test]
+    fn datum_of_another_game_is_an_error() {
+        let filename = temp_file("rtac_dataset_shape.mp");
+        save_dataset(&dataset(1, 0.0), &filename).unwrap();
+        assert!(matches!(
+            load_dataset::<42, 7>(&filename),
+            Err(FileError::ShapeMismatch {
+                expected: 42,
+                found: 9,
+                ..
+            })
+        ));
+    }
+
+    /// Saves a TicTacToe model, lets `edit` change the file and loads it
+    /// again.
+    fn load_edited_model(
+        name: &str,
+        edit: impl FnOnce(&mut ModelFile),
+    ) -> Result<MultiLayerPerceptron<9, 9>, FileError> {
+        let mut model = model_file::<TicTacToe, 9, 9>(&Default::default());
+        edit(&mut model);
+        let filename = temp_file(name);
+        std::fs::write(&filename, rmp_serde::to_vec_named(&model).unwrap()).unwrap();
+        load_model::<TicTacToe, 9, 9>(&filename)
+    }
+
+    #[test]
+    fn model_round_trip() {
+        let filename = temp_file("rtac_model_round_trip.mp");
+        let mlp = load_legacy_model(THOMAS).unwrap();
+        save_model::<TicTacToe, 9, 9>(&mlp, &filename).unwrap();
+        let loaded = load_model::<TicTacToe, 9, 9>(&filename).unwrap();
+        assert_eq!(flatten(&loaded.0), flatten(&mlp.0));
+        assert_eq!(flatten(&loaded.2), flatten(&mlp.2));
+        assert_eq!(flatten(&loaded.4 .0 .0 .0), flatten(&mlp.4 .0 .0 .0));
+        assert_eq!(flatten(&loaded.4 .0 .1 .0), flatten(&mlp.4 .0 .1 .0));
+    }
+
+    #[test]
+    fn missing_model_file_is_an_io_error() {
+        let filename = temp_file("rtac_model_that_does_not_exist.mp");
+        assert!(matches!(
+            load_model::<TicTacToe, 9, 9>(&filename),
+            Err(FileError::Io(error)) if error.kind() == io::ErrorKind::NotFound
+        ));
+    }
+
+    #[test]
+    fn model_file_that_is_not_messagepack_is_a_decode_error() {
+        let filename = temp_file("rtac_model_garbage.mp");
+        std::fs::write(&filename, b"not a model").unwrap();
+        assert!(matches!(
+            load_model::<TicTacToe, 9, 9>(&filename),
+            Err(FileError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn legacy_model_has_no_format_version() {
+        assert!(matches!(
+            load_model::<TicTacToe, 9, 9>(THOMAS),
+            Err(FileError::MissingFormatVersion)
+        ));
+    }
+
+    #[test]
+    fn model_of_another_version_is_rejected() {
+        let result = load_edited_model("rtac_model_version.mp", |model| {
+            model.format_version = MODEL_FORMAT_VERSION + 1;
+        });
+        assert!(matches!(
+            result,
+            Err(FileError::VersionMismatch { expected, found })
+                if expected == MODEL_FORMAT_VERSION && found == MODEL_FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn model_of_another_game_is_rejected() {
+        let filename = temp_file("rtac_model_game.mp");
+        save_model::<TicTacToe, 9, 9>(&Default::default(), &filename).unwrap();
+        assert!(matches!(
+            load_model::<MetaTac, 162, 81>(&filename),
+            Err(FileError::GameMismatch { expected, found })
+                if expected == MetaTac::NAME && found == TicTacToe::NAME
+        ));
+    }
+
+    #[test]
+    fn model_with_other_layers_is_rejected() {
+        let result = load_edited_model("rtac_model_architecture.mp", |model| {
+            model.layers.pop();
+        });
+        assert!(matches!(
+            result,
+            Err(FileError::ArchitectureMismatch { found }) if found.len() == 3
+        ));
+    }
+
+    #[test]
+    fn layer_with_missing_values_is_rejected() {
+        let result = load_edited_model("rtac_model_shape.mp", |model| {
+            model.layers[1].bias.pop();
+        });
+        assert!(matches!(
+            result,
+            Err(FileError::ShapeMismatch {
+                expected: 128,
+                found: 127,
+                ..
+            })
+        ));
+    }
+
+    fn small_trainer() -> Trainer<TicTacToe, 9, 9> {
+        let config = NetworkMctsConfig::new()
+            .with_power(10)
+            .with_samples_per_generation(20)
+            .with_dirichlet_noise(1.0, 0.25)
+            .with_augment_symmetries(true);
+        Trainer::new(config, TicTacToe::new()).with_epochs(2)
+    }
+
+    #[test]
+    fn resumed_training_matches_the_uninterrupted_run() {
+        let filename = temp_file("rtac_checkpoint_resume.mp");
+        let mut trainer = small_trainer().with_seed(7);
+        trainer.run_generation();
+        trainer.train_epochs::<8>();
+        save_checkpoint(&trainer, &filename).unwrap();
+
+        trainer.run_generation();
+        let losses = trainer.train_epochs::<8>();
+
+        // A fresh trainer with other random weights.
+        let mut resumed = load_checkpoint(small_trainer(), &filename).unwrap();
+        resumed.run_generation();
+        let resumed_losses = resumed.train_epochs::<8>();
+
+        assert_eq!(resumed.generation, trainer.generation);
+        assert_eq!(resumed_losses, losses);
+        let (model, resumed_model) = (&trainer.config.mlp, &resumed.config.mlp);
+        assert_eq!(flatten(&resumed_model.0), flatten(&model.0));
+        assert_eq!(flatten(&resumed_model.2), flatten(&model.2));
+        assert_eq!(
+            flatten(&resumed_model.4 .0 .0 .0),
+            flatten(&model.4 .0 .0 .0)
+        );
+        assert_eq!(
+            flatten(&resumed_model.4 .0 .1 .0),
+            flatten(&model.4 .0 .1 .0)
+        );
+    }
+
+    #[test]
+    fn legacy_model_saves_unchanged() {
+        let mlp = load_legacy_model(THOMAS).unwrap();
+        let filename = std::env::temp_dir().join("rtac_legacy_round_trip.mp");
+        let filename = filename.to_str().unwrap();
+        save_legacy_model(&mlp, filename).unwrap();
+
+        // The keys may be written in another order, so compare the arrays.
+        let read = |filename| -> LegacyModel {
+            rmp_serde::from_slice(&std::fs::read(filename).unwrap()).unwrap()
+        };
+        assert_eq!(read(filename), read(THOMAS));
+    }
+
+    #[test]
+    fn legacy_model_plays_tictactoe() {
+        let mlp = load_legacy_model(THOMAS).unwrap();
+        // The opening, a block and a win. With transposed weights, the
+        // network picks none of them.
+        let positions = [
+            (vec![], (1, 1)),
+            (vec![(1, 1), (0, 0), (2, 2), (0, 1)], (0, 2)),
+            (vec![(0, 0), (1, 1), (2, 2), (1, 0), (0, 2)], (1, 2)),
+        ];
+
+        for (actions, expected) in positions {
+            let mut state = TicTacToe::new();
+            for action in actions {
+                state.apply_action(action);
+            }
+            let (policy, _) = mlp.forward(state.tensorize());
+            let best = state
+                .get_actions()
+                .into_iter()
+                .max_by(|a, b| {
+                    let a = policy.data()[TicTacToe::action_to_index(*a)];
+                    let b = policy.data()[TicTacToe::action_to_index(*b)];
+                    a.total_cmp(&b)
+                })
+                .unwrap();
+            assert_eq!(best, expected, "in position\n{}", state);
+        }
+    }
 }
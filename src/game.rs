@@ -1,10 +1,12 @@
 /// Defines a trait for generic games on which we can run MCTS.
 use std::{
+    collections::hash_map::DefaultHasher,
     fmt::{Debug, Display},
+    hash::{Hash, Hasher},
     ops::Not,
 };
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum Player {
     X,
     O,
@@ -35,7 +37,7 @@ impl VictoryState {
 }
 
 pub trait Game: Clone + Debug + Display {
-    type Action: Copy + Debug;
+    type Action: Copy + Debug + PartialEq;
 
     fn get_actions(&self) -> Vec<Self::Action>;
 
@@ -49,6 +51,60 @@ pub trait Game: Clone + Debug + Display {
     }
 }
 
+/// Identifies a position, so the same position reached by different move
+/// orders can be shared in a search. It must cover everything that matters
+/// for the rest of the game, including the player to move. Equal positions
+/// must have equal hashes, but different positions may collide.
+///
+/// Every [Hash] type gets this by hashing the whole state. A game can
+/// provide something cheaper, such as Zobrist hashing, by implementing this
+/// instead of [Hash].
+pub trait StateHash {
+    fn state_hash(&self) -> u64;
+}
+
+impl<T: Hash> StateHash for T {
+    fn state_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// One of the eight symmetries of a square board: an optional transposition
+/// followed by optional flips of the x and y coordinates.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SquareSymmetry {
+    pub transpose: bool,
+    pub flip_x: bool,
+    pub flip_y: bool,
+}
+
+impl SquareSymmetry {
+    pub const IDENTITY: SquareSymmetry = SquareSymmetry {
+        transpose: false,
+        flip_x: false,
+        flip_y: false,
+    };
+
+    /// All eight symmetries, starting with the identity.
+    pub fn all() -> impl Iterator<Item = SquareSymmetry> {
+        (0..8).map(|i| SquareSymmetry {
+            transpose: i & 1 != 0,
+            flip_x: i & 2 != 0,
+            flip_y: i & 4 != 0,
+        })
+    }
+
+    /// Maps (x, y) on a board with side length `size`.
+    pub fn apply(self, (x, y): (usize, usize), size: usize) -> (usize, usize) {
+        let (x, y) = if self.transpose { (y, x) } else { (x, y) };
+        let x = if self.flip_x { size - 1 - x } else { x };
+        let y = if self.flip_y { size - 1 - y } else { y };
+        (x, y)
+    }
+}
+
 // pub trait GameMetadata {
 //     type Game: Game;
 //     fn initial_state() -> Self::Game;
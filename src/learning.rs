@@ -1,130 +1,669 @@
-use dfdx::tensor::{HasArrayData, Tensor1D, Tensor2D, TensorCreator};
+use std::collections::VecDeque;
+
+use dfdx::{
+    prelude::{
+        clamp, div_scalar, ln, mul, negate, square, sub, sum, Module, Optimizer, OwnedTape, Sgd,
+        SgdConfig,
+    },
+    tensor::{HasArrayData, PutTape, Tensor, Tensor0D, Tensor1D, Tensor2D, TensorCreator},
+};
+
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::StdRng,
+    seq::SliceRandom,
+    Rng, SeedableRng,
+};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    game::Game,
-    mcts::MctsConfigTrait,
-    nn::{tensorize, NetworkMctsConfig},
-    tictactoe::{action_to_index, index_to_action, TicTacToe},
+    game::Player,
+    mcts::{score_terminal_victory_state, SearchResult, SelectionPolicy, Tree},
+    nn::{MultiLayerPerceptron, NetworkGame, NetworkMctsConfig, EVALUATION_BATCH_SIZE},
 };
 
-#[derive(Debug)]
-pub struct TrainingDatum {
-    pub input: Tensor1D<9>,
-    pub improved_policy: Tensor1D<9>,
+#[derive(Debug, Clone)]
+pub struct TrainingDatum<const I: usize, const A: usize> {
+    pub input: Tensor1D<I>,
+    pub improved_policy: Tensor1D<A>,
     pub expected_value: f32,
 }
 
+/// A batch of training data as tensors. If there are fewer samples than
+/// rows, the remaining rows are padding and masked out of the loss.
 #[derive(Debug)]
-pub struct TrainingData<const N: usize> {
-    pub input: Tensor2D<N, 9>,
-    pub improved_policy: Tensor2D<N, 9>,
+pub struct TrainingData<const N: usize, const I: usize, const A: usize> {
+    pub input: Tensor2D<N, I>,
+    pub improved_policy: Tensor2D<N, A>,
     pub expected_value: Tensor2D<N, 1>,
+    /// One for rows holding a sample, zero for padding rows.
+    pub mask: Tensor2D<N, 1>,
+    /// Number of rows holding a sample.
+    pub len: usize,
 }
 
-impl<const N: usize> TrainingData<N> {
-    pub fn new(data: &[TrainingDatum]) -> Self {
+impl<const N: usize, const I: usize, const A: usize> TrainingData<N, I, A> {
+    /// Panics, if there are more than `N` samples.
+    pub fn new(data: &[TrainingDatum<I, A>]) -> Self {
+        assert!(
+            data.len() <= N,
+            "Got {} samples for a batch of {} rows",
+            data.len(),
+            N
+        );
+
         let mut input = Tensor2D::zeros();
         let mut improved_policy = Tensor2D::zeros();
         let mut expected_value = Tensor2D::zeros();
+        let mut mask = Tensor2D::zeros();
 
         for (i, datum) in data.iter().enumerate() {
             input.mut_data()[i].copy_from_slice(datum.input.data());
             improved_policy.mut_data()[i].copy_from_slice(datum.improved_policy.data());
             expected_value.mut_data()[i][0] = datum.expected_value;
+            mask.mut_data()[i][0] = 1.0;
         }
 
         TrainingData {
             input,
             improved_policy,
             expected_value,
+            mask,
+            len: data.len(),
         }
     }
 }
 
-fn one_training_step(state: &TicTacToe, config: NetworkMctsConfig) -> Option<TrainingDatum> {
-    // If the state is already terminal, there is no point in training on it.
-    if state.get_victory_state().is_terminal() {
-        return None;
+/// Training data of arbitrary length. It is turned into tensors one
+/// minibatch at a time.
+#[derive(Debug, Default)]
+pub struct TrainingDataset<const I: usize, const A: usize> {
+    pub data: Vec<TrainingDatum<I, A>>,
+}
+
+impl<const I: usize, const A: usize> TrainingDataset<I, A> {
+    pub fn len(&self) -> usize {
+        self.data.len()
     }
 
-    let mut node = config.node_for_new_state(state.clone()).0;
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
 
-    for _ in 0..config.power {
-        node.walk_to_leaf(&config);
+    /// Splits the dataset into minibatches of `B` samples in the current
+    /// order. The last minibatch may be smaller, its padding rows are masked.
+    pub fn minibatches<const B: usize>(&self) -> impl Iterator<Item = TrainingData<B, I, A>> + '_ {
+        self.data.chunks(B).map(TrainingData::new)
     }
+}
 
-    // We want to apply the softmax to the expected reward to determine the
-    // improved policy.
+/// How a [ReplayBuffer] picks samples.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub enum SamplingStrategy {
+    /// Every sample is equally likely, no sample is drawn twice.
+    Uniform,
+    /// A sample is `decay` times as likely as a sample of the next newer
+    /// generation. Samples are drawn with replacement.
+    RecencyWeighted { decay: f32 },
+}
 
-    let mut output: [f32; 9] = [0.0; 9];
-    let mut sum: f32 = 0.0;
-    let mut best_value = f32::NEG_INFINITY;
+/// Keeps the training data of the last few self-play generations, so the
+/// network isn't only trained on the latest handful of games.
+#[derive(Debug)]
+pub struct ReplayBuffer<const I: usize, const A: usize> {
+    /// Newest generation first.
+    generations: VecDeque<Vec<TrainingDatum<I, A>>>,
+    /// Maximal number of generations that are kept.
+    capacity: usize,
+    pub sampling: SamplingStrategy,
+}
 
-    // Get maximum visit count to prevent numeric problems with the softmax.
-    let max_visit_count = node
+impl<const I: usize, const A: usize> ReplayBuffer<I, A> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(
+            capacity > 0,
+            "A replay buffer must keep at least one generation"
+        );
+        ReplayBuffer {
+            generations: VecDeque::with_capacity(capacity),
+            capacity,
+            sampling: SamplingStrategy::Uniform,
+        }
+    }
+    pub fn with_sampling(self, sampling: SamplingStrategy) -> Self {
+        ReplayBuffer { sampling, ..self }
+    }
+
+    /// Adds a new generation, dropping the oldest one if the buffer is full.
+    pub fn push_generation(&mut self, dataset: TrainingDataset<I, A>) {
+        if self.generations.len() == self.capacity {
+            self.generations.pop_back();
+        }
+        self.generations.push_front(dataset.data);
+    }
+
+    /// Maximal number of generations that are kept.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The data of every generation, newest generation first.
+    pub fn generations(&self) -> impl Iterator<Item = &[TrainingDatum<I, A>]> {
+        self.generations.iter().map(Vec::as_slice)
+    }
+
+    /// Total number of samples over all generations.
+    pub fn len(&self) -> usize {
+        self.generations.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Draws `count` samples according to the sampling strategy. Uniform
+    /// sampling returns at most all samples of the buffer.
+    pub fn sample<R: Rng>(&self, count: usize, rng: &mut R) -> TrainingDataset<I, A> {
+        let data = match self.sampling {
+            SamplingStrategy::Uniform => self
+                .generations
+                .iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .choose_multiple(rng, count)
+                .map(|&datum| datum.clone())
+                .collect(),
+            SamplingStrategy::RecencyWeighted { decay } => {
+                let weights = self
+                    .generations
+                    .iter()
+                    .enumerate()
+                    .map(|(age, generation)| decay.powi(age as i32) * generation.len() as f32);
+                let Ok(generation_index) = WeightedIndex::new(weights) else {
+                    return TrainingDataset::default();
+                };
+
+                (0..count)
+                    .map(|_| {
+                        let generation = &self.generations[generation_index.sample(rng)];
+                        generation
+                            .choose(rng)
+                            .expect("Empty generations have zero weight")
+                            .clone()
+                    })
+                    .collect()
+            }
+        };
+
+        TrainingDataset { data }
+    }
+}
+
+/// A searched position of a self-play game. It only becomes a
+/// [TrainingDatum] once the game is over and its outcome is known.
+struct SearchedPosition<G, const A: usize> {
+    state: G,
+    improved_policy: Tensor1D<A>,
+    /// Mean value of the search at this position, from the perspective of
+    /// the player to move.
+    search_value: f32,
+    player: Player,
+}
+
+/// Searches the tree until its root has been visited `config.power` times.
+/// Visits that were accumulated before, e.g. while searching the parent,
+/// count towards this.
+fn one_training_step<G: NetworkGame<I, A>, const I: usize, const A: usize>(
+    tree: &mut Tree<G>,
+    config: &NetworkMctsConfig<G, I, A>,
+    rng: &mut impl Rng,
+) -> Option<(SearchedPosition<G, A>, SearchResult<G>)> {
+    // If the state is already terminal, there is no point in training on it.
+    if tree.root().state.get_victory_state().is_terminal() {
+        return None;
+    }
+
+    // This is the root of the search, so this is where exploration noise
+    // goes.
+    tree.add_dirichlet_noise(config.dirichlet_alpha, config.dirichlet_weight, rng);
+
+    let remaining = config
+        .power
+        .saturating_sub(tree.root().visit_count as usize);
+    let result = tree.batched_search(config, remaining, EVALUATION_BATCH_SIZE);
+
+    // The improved policy follows the visit counts, sharpened or flattened
+    // by the temperature.
+    let mut output: [f32; A] = [0.0; A];
+    for (child, probability) in result
         .children
         .iter()
-        .map(|c| c.visit_count)
-        // .max() does not work, because f32 isn't Eq.
-        .max_by(|x, y| x.abs().partial_cmp(&y.abs()).unwrap())
-        .unwrap();
-
-    for edge in node.children.iter() {
-        let value = ((edge.visit_count - max_visit_count) / config.temperature).exp();
-        output[action_to_index(edge.action)] = value;
-        sum += value;
-        if value > best_value {
-            best_value = edge.expected_reward;
-        }
+        .zip(result.visit_distribution(config.temperature))
+    {
+        output[G::action_to_index(child.action)] = probability;
     }
-    output.iter_mut().for_each(|x| *x /= sum);
 
-    let improved_policy = Tensor1D::new(output);
+    let state = tree.root().state.clone();
+    let position = SearchedPosition {
+        improved_policy: Tensor1D::new(output),
+        search_value: result.value,
+        player: state.get_player(),
+        state,
+    };
+    Some((position, result))
+}
+
+/// Plays one game of self-play and labels every position with the final
+/// outcome of the game, blended with the search value by
+/// `config.search_value_weight`. The states are returned along with the
+/// training data, so they can be augmented.
+fn play_training_game<G: NetworkGame<I, A>, const I: usize, const A: usize>(
+    config: &NetworkMctsConfig<G, I, A>,
+    initial_state: &G,
+    rng: &mut impl Rng,
+) -> Vec<(G, TrainingDatum<I, A>)> {
+    let mut positions = Vec::new();
+
+    // The search tree is carried along the played actions, so it doesn't
+    // start from scratch on every move.
+    let mut tree = Tree::new(initial_state.clone(), config);
+    while !tree.root().state.get_victory_state().is_terminal() {
+        let (position, result) = one_training_step(&mut tree, config, rng)
+            .expect("Didn't get any training data from a non-terminal state.");
+
+        // Sample the action from the same distribution as the improved policy.
+        let action = result
+            .select_with_rng(&SelectionPolicy::Temperature(config.temperature), rng)
+            .expect("A non-terminal state has actions");
+        tree = tree.advance(action, config);
+
+        positions.push(position);
+    }
 
-    Some(TrainingDatum {
-        input: tensorize(state),
-        improved_policy,
-        expected_value: best_value,
-    })
+    let weight = config.search_value_weight;
+    positions
+        .into_iter()
+        .map(|position| {
+            let outcome = score_terminal_victory_state(&tree.root().state, position.player);
+            let datum = TrainingDatum {
+                input: position.state.tensorize(),
+                improved_policy: position.improved_policy,
+                expected_value: (1.0 - weight) * outcome + weight * position.search_value,
+            };
+            (position.state, datum)
+        })
+        .collect()
 }
 
-pub fn generate_training_data<const N: usize>(config: &NetworkMctsConfig) -> TrainingData<N> {
-    let mut data = Vec::with_capacity(N);
+/// Plays self-play games until there are `config.samples_per_generation`
+/// positions. All random choices, including those of random symmetry
+/// inference, come from `rng`, so the data only depends on its state.
+pub fn generate_training_data<G: NetworkGame<I, A>, const I: usize, const A: usize>(
+    config: &NetworkMctsConfig<G, I, A>,
+    initial_state: &G,
+    rng: &mut impl Rng,
+) -> TrainingDataset<I, A> {
+    config.reseed(rng.gen());
+    let mut data = Vec::with_capacity(config.samples_per_generation);
+    let mut positions = 0;
 
-    // Collect training data.
-    while data.len() < config.batch_size {
-        // Play out one game driven by the training:
-        let mut state = TicTacToe::new();
-        while !state.get_victory_state().is_terminal() && data.len() < config.batch_size {
-            let datum = one_training_step(&state, config.clone());
-            if let Some(datum) = datum {
-                let random_distribution = datum.improved_policy.data();
-                let action_index = sample_index_from_distribution(random_distribution);
-                let action = index_to_action(action_index);
-                state.apply_action(action);
+    // Collect training data. Games are always played to the end, because
+    // the value target depends on the outcome.
+    while positions < config.samples_per_generation {
+        for (state, datum) in play_training_game(config, initial_state, rng) {
+            positions += 1;
+            if config.augment_symmetries {
+                data.extend(symmetric_data(&state, &datum));
+            }
+            data.push(datum);
+        }
+    }
 
-                data.push(datum);
-            } else {
-                panic!("Didn't get any training data from a non-terminal state.");
+    TrainingDataset { data }
+}
+
+/// Returns the datum for every symmetric variant of the state, with the
+/// improved policy permuted accordingly.
+fn symmetric_data<G: NetworkGame<I, A>, const I: usize, const A: usize>(
+    state: &G,
+    datum: &TrainingDatum<I, A>,
+) -> Vec<TrainingDatum<I, A>> {
+    state
+        .symmetries()
+        .into_iter()
+        .map(|(variant, permutation)| {
+            let mut policy = [0.0; A];
+            for (i, probability) in datum.improved_policy.data().iter().enumerate() {
+                policy[permutation[i]] = *probability;
+            }
+            TrainingDatum {
+                input: variant.tensorize(),
+                improved_policy: Tensor1D::new(policy),
+                expected_value: datum.expected_value,
             }
+        })
+        .collect()
+}
+
+/// Weights of the terms in the combined AlphaZero loss.
+#[derive(Debug, Clone, Copy)]
+pub struct LossConfig {
+    pub policy_weight: f32,
+    pub value_weight: f32,
+    /// Factor of the squared weights of all linear layers. This is applied
+    /// as weight decay after the optimizer step, biases are not regularized.
+    pub l2_regularization: f32,
+}
+
+impl Default for LossConfig {
+    fn default() -> Self {
+        Self {
+            policy_weight: 1.0,
+            value_weight: 1.0,
+            l2_regularization: 1e-4,
         }
     }
+}
 
-    TrainingData::new(&data)
+/// The individual loss components of one training step, for logging.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Losses {
+    pub policy: f32,
+    pub value: f32,
+    pub l2: f32,
+    pub total: f32,
 }
 
-fn sample_index_from_distribution(random_distribution: &[f32]) -> usize {
-    // Sample an action from the posterior policy:
-    // This means we pull a random index weighted by datum.improved_policy.
-    let random_float = rand::random::<f32>();
-    let mut cumulative_probability = 0.0;
-    let mut action_index = 0;
-    for (i, probability) in random_distribution.iter().enumerate() {
-        cumulative_probability += probability;
-        if cumulative_probability > random_float {
-            action_index = i;
-            break;
+impl Losses {
+    pub fn mean(losses: &[Losses]) -> Losses {
+        let n = losses.len() as f32;
+        Losses {
+            policy: losses.iter().map(|l| l.policy).sum::<f32>() / n,
+            value: losses.iter().map(|l| l.value).sum::<f32>() / n,
+            l2: losses.iter().map(|l| l.l2).sum::<f32>() / n,
+            total: losses.iter().map(|l| l.total).sum::<f32>() / n,
         }
     }
-    action_index
+}
+
+/// Runs one optimizer step on the combined loss: cross entropy between the
+/// predicted policy and the improved policy, plus the squared error of the
+/// value head, plus L2 regularization.
+pub fn training_step<const N: usize, const I: usize, const A: usize>(
+    mlp: &mut MultiLayerPerceptron<I, A>,
+    sgd: &mut Sgd<MultiLayerPerceptron<I, A>>,
+    data: &TrainingData<N, I, A>,
+    loss_config: &LossConfig,
+) -> Losses {
+    let l2 = loss_config.l2_regularization * l2_penalty(mlp);
+
+    let (pred_policy, pred_value) = mlp.forward(data.input.trace());
+
+    // NOTE: we also have to move the tape around when computing losses
+    let value_loss = masked_mse_loss(pred_value, data);
+    let value = *value_loss.data();
+    let (value_loss, tape) = (value_loss * loss_config.value_weight).split_tape();
+
+    let policy_loss = cross_entropy_loss(pred_policy.put_tape(tape), data);
+    let policy = *policy_loss.data();
+
+    let loss = policy_loss * loss_config.policy_weight + &value_loss;
+    let total = *loss.data() + l2;
+
+    let gradients = loss.backward();
+    sgd.update(mlp, gradients).expect("Unused params");
+
+    let decay = 2.0 * sgd.cfg.lr * loss_config.l2_regularization;
+    apply_weight_decay(mlp, decay);
+
+    Losses {
+        policy,
+        value,
+        l2,
+        total,
+    }
+}
+
+/// Cross entropy between predicted probabilities and target distributions,
+/// averaged over the samples of the batch.
+fn cross_entropy_loss<const N: usize, const I: usize, const A: usize>(
+    pred: Tensor2D<N, A, OwnedTape>,
+    data: &TrainingData<N, I, A>,
+) -> Tensor0D<OwnedTape> {
+    // Clamp, so a vanishing probability doesn't turn into an infinite loss.
+    let log_pred = ln(clamp(pred, 1e-7, 1.0));
+    // Padding rows have an all-zero target, so they don't contribute.
+    let cross_entropy = negate(sum(mul(log_pred, &data.improved_policy)));
+    div_scalar(cross_entropy, data.len as f32)
+}
+
+/// Squared error of the value prediction, averaged over the samples of the
+/// batch.
+fn masked_mse_loss<const N: usize, const I: usize, const A: usize>(
+    pred: Tensor2D<N, 1, OwnedTape>,
+    data: &TrainingData<N, I, A>,
+) -> Tensor0D<OwnedTape> {
+    let squared_error = square(sub(pred, &data.expected_value));
+    div_scalar(sum(mul(squared_error, &data.mask)), data.len as f32)
+}
+
+/// Sum of the squared weights of all linear layers.
+fn l2_penalty<const I: usize, const A: usize>(mlp: &MultiLayerPerceptron<I, A>) -> f32 {
+    let ((policy_head, _), (value_head, _)) = &mlp.4 .0;
+
+    let squares = |x: &f32| x * x;
+    mlp.0
+        .weight
+        .data()
+        .iter()
+        .flatten()
+        .map(squares)
+        .sum::<f32>()
+        + mlp
+            .2
+            .weight
+            .data()
+            .iter()
+            .flatten()
+            .map(squares)
+            .sum::<f32>()
+        + policy_head
+            .weight
+            .data()
+            .iter()
+            .flatten()
+            .map(squares)
+            .sum::<f32>()
+        + value_head
+            .weight
+            .data()
+            .iter()
+            .flatten()
+            .map(squares)
+            .sum::<f32>()
+}
+
+/// Shrinks the weights of all linear layers by the given factor.
+fn apply_weight_decay<const I: usize, const A: usize>(
+    mlp: &mut MultiLayerPerceptron<I, A>,
+    decay: f32,
+) {
+    let mut shrink = |x: &mut f32| *x -= decay * *x;
+    mlp.0
+        .weight
+        .mut_data()
+        .iter_mut()
+        .flatten()
+        .for_each(&mut shrink);
+    mlp.2
+        .weight
+        .mut_data()
+        .iter_mut()
+        .flatten()
+        .for_each(&mut shrink);
+
+    let ((policy_head, _), (value_head, _)) = &mut mlp.4 .0;
+    policy_head
+        .weight
+        .mut_data()
+        .iter_mut()
+        .flatten()
+        .for_each(&mut shrink);
+    value_head
+        .weight
+        .mut_data()
+        .iter_mut()
+        .flatten()
+        .for_each(&mut shrink);
+}
+
+/// Owns everything a training run needs, so the loop of generating self-play
+/// data and training on it can be driven from any binary.
+pub struct Trainer<G: NetworkGame<I, A>, const I: usize, const A: usize> {
+    /// The search config, which also holds the model that is trained.
+    pub config: NetworkMctsConfig<G, I, A>,
+    pub sgd: Sgd<MultiLayerPerceptron<I, A>>,
+    pub loss_config: LossConfig,
+    /// Holds the data of the last few generations that is trained on.
+    pub replay_buffer: ReplayBuffer<I, A>,
+    /// Number of passes over the replay buffer after each generation.
+    pub epochs: usize,
+    /// Number of generations that were run so far.
+    pub generation: usize,
+    /// Seed of all random choices of self-play and training, see
+    /// [Trainer::with_seed].
+    pub seed: u64,
+    initial_state: G,
+}
+
+/// What a random number generator of a [Trainer] is used for, so self-play
+/// and training don't draw the same numbers.
+#[derive(Clone, Copy)]
+enum RngStream {
+    SelfPlay = 0,
+    Training = 1,
+}
+
+/// The random number generators of a generation only depend on the seed and
+/// the generation, so a training run can be resumed from these two numbers
+/// alone. The three numbers are mixed, so runs with nearby seeds don't share
+/// the generators of different generations.
+fn generation_rng(seed: u64, generation: usize, stream: RngStream) -> StdRng {
+    let key = mix(mix(mix(seed) ^ generation as u64) ^ stream as u64);
+    StdRng::seed_from_u64(key)
+}
+
+/// The finalizer of SplitMix64. It is a bijection where every input bit
+/// affects every output bit, and unlike std's hashers it is the same on
+/// every platform and Rust version.
+fn mix(x: u64) -> u64 {
+    let x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+impl<G: NetworkGame<I, A>, const I: usize, const A: usize> Trainer<G, I, A> {
+    /// Self-play games start from `initial_state`.
+    pub fn new(config: NetworkMctsConfig<G, I, A>, initial_state: G) -> Self {
+        let seed = rand::random();
+        Trainer {
+            config,
+            sgd: Default::default(),
+            loss_config: Default::default(),
+            replay_buffer: ReplayBuffer::new(4),
+            epochs: 2,
+            generation: 0,
+            seed,
+            initial_state,
+        }
+    }
+    pub fn with_sgd_config(self, sgd_config: SgdConfig) -> Self {
+        Trainer {
+            sgd: Sgd::new(sgd_config),
+            ..self
+        }
+    }
+    pub fn with_loss_config(self, loss_config: LossConfig) -> Self {
+        Trainer {
+            loss_config,
+            ..self
+        }
+    }
+    pub fn with_replay_buffer(self, replay_buffer: ReplayBuffer<I, A>) -> Self {
+        Trainer {
+            replay_buffer,
+            ..self
+        }
+    }
+    pub fn with_epochs(self, epochs: usize) -> Self {
+        Trainer { epochs, ..self }
+    }
+    /// Makes self-play and sampling from the replay buffer reproducible.
+    pub fn with_seed(self, seed: u64) -> Self {
+        Trainer { seed, ..self }
+    }
+
+    /// Generates the self-play data of the next generation with the current
+    /// model and adds it to the replay buffer. Returns the number of new
+    /// samples.
+    pub fn run_generation(&mut self) -> usize {
+        self.generation += 1;
+        let mut rng = generation_rng(self.seed, self.generation, RngStream::SelfPlay);
+        let dataset = generate_training_data(&self.config, &self.initial_state, &mut rng);
+        let count = dataset.len();
+        self.replay_buffer.push_generation(dataset);
+        count
+    }
+
+    /// Trains the model for the configured number of epochs. Every epoch
+    /// draws as many samples from the replay buffer as it holds and takes one
+    /// optimizer step per minibatch of `B` samples. Returns the mean losses
+    /// of every epoch.
+    pub fn train_epochs<const B: usize>(&mut self) -> Vec<Losses> {
+        let mut rng = generation_rng(self.seed, self.generation, RngStream::Training);
+        (0..self.epochs)
+            .map(|_| {
+                let dataset = self
+                    .replay_buffer
+                    .sample(self.replay_buffer.len(), &mut rng);
+                let losses: Vec<Losses> = dataset
+                    .minibatches::<B>()
+                    .map(|batch| {
+                        training_step(
+                            &mut self.config.mlp,
+                            &mut self.sgd,
+                            &batch,
+                            &self.loss_config,
+                        )
+                    })
+                    .collect();
+                Losses::mean(&losses)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generation_rngs_of_nearby_seeds_differ() {
+        let first_value =
+            |seed, generation, stream| -> u64 { generation_rng(seed, generation, stream).gen() };
+        let mut values = Vec::new();
+        for seed in 0..4 {
+            for generation in 0..4 {
+                values.push(first_value(seed, generation, RngStream::SelfPlay));
+                values.push(first_value(seed, generation, RngStream::Training));
+            }
+        }
+
+        // The generators must not repeat when the seed goes up by the
+        // difference of two generations or streams.
+        let count = values.len();
+        values.sort_unstable();
+        values.dedup();
+        assert_eq!(values.len(), count);
+    }
 }
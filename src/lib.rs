@@ -0,0 +1,9 @@
+pub mod connect_four;
+pub mod files;
+pub mod game;
+pub mod learning;
+pub mod mcts;
+pub mod meta_tac;
+pub mod nn;
+pub mod tictactoe;
+pub mod transposition;
@@ -1,148 +1,172 @@
-mod files;
-mod game;
-mod learning;
-mod mcts;
-mod nn;
-mod tictactoe;
-
-use std::time::Instant;
-
-use dfdx::prelude::{Optimizer, Sgd};
-use dfdx::tensor::{HasArrayData, PutTape};
-use dfdx::{
-    prelude::{mse_loss, Module},
-    tensor::Tensor,
-};
-use mcts::MctsConfigTrait;
-use tictactoe::TicTacToe;
-
-use crate::game::Game;
-use crate::learning::TrainingData;
-use crate::mcts::RolloutMctsConfig;
-use crate::nn::{tensorize, MultiLayerPerceptron, NetworkMctsConfig};
+use std::time::{Duration, Instant};
+
+use dfdx::tensor::HasArrayData;
+use rtac::connect_four::ConnectFour;
+use rtac::files;
+use rtac::game::{Game, StateHash};
+use rtac::learning::{ReplayBuffer, SamplingStrategy, Trainer};
+use rtac::mcts::{MctsConfigTrait, RolloutMctsConfig, SearchLimits, SelectionPolicy, Tree};
+use rtac::meta_tac::MetaTac;
+use rtac::nn::{NetworkGame, NetworkMctsConfig};
+use rtac::tictactoe::TicTacToe;
+use rtac::transposition::SearchGraph;
 
 fn main() {
     // Load Thomas model and apply it to an empty board.
-    let mlp_thomas = files::load_model("thomas.mp").unwrap();
+    let mlp_thomas = files::load_legacy_model("thomas.mp").unwrap();
     println!("{:#?}", mlp_thomas.0);
-    let config_thomas = NetworkMctsConfig {
-        mlp: mlp_thomas,
-        temperature: 1.0,
-        power: 100,
-        batch_size: 100,
-    };
+    let config_thomas: NetworkMctsConfig<TicTacToe, 9, 9> = NetworkMctsConfig::from_mlp(mlp_thomas)
+        .with_power(100)
+        .with_samples_per_generation(100);
 
     let state = TicTacToe::new();
-    println!("{:?}", tensorize(&state));
+    println!("{:?}", state.tensorize());
     let (node_thomas, value_thomas) = config_thomas.node_for_new_state(state);
     println!("Value: {}", value_thomas);
     println!("Thomas: \n{:?}", node_thomas);
 
     let ttt = TicTacToe::new();
 
-    let mut config: NetworkMctsConfig = nn::NetworkMctsConfig::new()
+    let config: NetworkMctsConfig<TicTacToe, 9, 9> = NetworkMctsConfig::new()
         .with_power(100)
         .with_temperature(1.0)
-        .with_batch_size(100);
-
-    let mut node = config.node_for_new_state(ttt).0;
+        .with_samples_per_generation(100)
+        .with_dirichlet_noise(1.0, 0.25)
+        .with_search_value_weight(0.5)
+        .with_augment_symmetries(true);
 
-    for _ in 0..10_000 {
-        node.walk_to_leaf(&config);
-    }
+    let mut tree = Tree::new(ttt, &config);
 
-    // Output the values for all actions:
-    for edge in node.children.iter() {
-        println!(
-            "Action {:?} has value {:+.4} and was visited {} times. (Prior: {})",
-            edge.action, edge.expected_reward, edge.visit_count, edge.prior_probability
-        );
-    }
+    let result = tree.search(&config, &SearchLimits::new().with_playouts(10_000));
+    println!("{}", result);
 
     // Do a few training loops
+    let replay_buffer =
+        ReplayBuffer::new(4).with_sampling(SamplingStrategy::RecencyWeighted { decay: 0.5 });
+    let mut trainer = Trainer::new(config, TicTacToe::new()).with_replay_buffer(replay_buffer);
     for i_training in 0..2 {
         println!("Training loop {}", i_training);
         // Get some training data
-        let training_data: TrainingData<10000> = learning::generate_training_data(&config);
-        println!("Training data generated");
-
-        let x = training_data.input;
-        let y1 = training_data.improved_policy;
-        let y2 = training_data.expected_value;
-
-        let mut sgd: Sgd<MultiLayerPerceptron> = Default::default();
-
-        for i_epoch in 0..2 {
-            // Train one epoch
-            let start = Instant::now();
-            let x = x.trace();
-            let (pred1, pred2) = config.mlp.forward(x);
-
-            // NOTE: we also have to move the tape around when computing losses
-            let (loss2, tape) = mse_loss(pred2, &y2).split_tape();
-            // TODO: This should use some cross entropy loss function
-            let loss1 = mse_loss(pred1.put_tape(tape), &y1);
-
-            let losses = [*loss1.data(), *loss2.data()];
-            let loss = loss1 + &loss2;
-            let gradients = loss.backward();
-            sgd.update(&mut config.mlp, gradients)
-                .expect("Unused params");
+        let start = Instant::now();
+        let count = trainer.run_generation();
+        println!("{} samples generated in {:?}", count, start.elapsed());
 
+        let losses = trainer.train_epochs::<32>();
+        for (i_epoch, losses) in losses.iter().enumerate() {
             println!(
-                "losses={:.3?} in {:?} -- epoch {}",
-                losses,
-                start.elapsed(),
-                i_epoch
+                "policy={:.3} value={:.3} l2={:.3} total={:.3} -- epoch {}",
+                losses.policy, losses.value, losses.l2, losses.total, i_epoch
             );
         }
     }
+
+    // Resume from a checkpoint, as if the run had been interrupted.
+    let checkpoint = std::env::temp_dir().join("rtac_checkpoint.mp");
+    let checkpoint = checkpoint.to_str().unwrap();
+    files::save_checkpoint(&trainer, checkpoint).unwrap();
+    let resumed = files::load_checkpoint(
+        Trainer::new(NetworkMctsConfig::new(), TicTacToe::new()),
+        checkpoint,
+    )
+    .unwrap();
+    assert_eq!(resumed.generation, trainer.generation);
+    assert_eq!(resumed.replay_buffer.len(), trainer.replay_buffer.len());
+    assert_eq!(
+        resumed.config.mlp.0.weight.data(),
+        trainer.config.mlp.0.weight.data()
+    );
+    println!(
+        "Resumed generation {} with {} samples from {}",
+        resumed.generation,
+        resumed.replay_buffer.len(),
+        checkpoint
+    );
+
+    let config = trainer.config;
     let state = TicTacToe::new();
 
     model_mcts_example(&config, state.clone());
     plain_mcts_example(state);
+    plain_mcts_example(ConnectFour::new());
+    plain_mcts_example(MetaTac::new());
+    parallel_mcts_example(ConnectFour::new());
+    transposition_mcts_example(TicTacToe::new());
 
-    files::save_model(config.mlp.clone(), "model.mp").unwrap();
-    let mlp2 = files::load_model("model.mp").unwrap();
+    files::save_model::<TicTacToe, 9, 9>(&config.mlp, "model.mp").unwrap();
+    let mlp2 = files::load_model::<TicTacToe, 9, 9>("model.mp").unwrap();
 
     // Check that the layers are equal by comparing data().
     assert_eq!(config.mlp.0.weight.data(), mlp2.0.weight.data());
-    files::save_model(mlp2, "model2.mp").unwrap();
+    files::save_model::<TicTacToe, 9, 9>(&mlp2, "model2.mp").unwrap();
 }
 
-fn model_mcts_example(config: &NetworkMctsConfig, mut state: TicTacToe) {
+fn model_mcts_example(config: &NetworkMctsConfig<TicTacToe, 9, 9>, mut state: TicTacToe) {
     // Model intuition after training:
     state.apply_action((0, 0));
     println!();
     println!("{}", state);
     println!("Testing mcts after training");
-    let mut node = config.node_for_new_state(state.clone()).0;
-    for _ in 0..100 {
-        node.walk_to_leaf(config);
-    }
-    // Output the values for all actions:
-    for edge in node.children.iter() {
-        println!(
-            "Action {:?} has value {:+.4} and was visited {} times. (Prior: {})",
-            edge.action, edge.expected_reward, edge.visit_count, edge.prior_probability
-        );
+    let mut tree = Tree::new(state.clone(), config);
+    let result = tree.search(config, &SearchLimits::new().with_playouts(100));
+    println!("{}", result);
+    for policy in [
+        SelectionPolicy::MaxVisits,
+        SelectionPolicy::MaxValue,
+        SelectionPolicy::Temperature(1.0),
+        SelectionPolicy::LowerConfidenceBound(1.0),
+    ] {
+        println!("{:?} picks {:?}", policy, result.select(&policy).unwrap());
     }
 }
 
-fn plain_mcts_example(state: TicTacToe) {
+fn plain_mcts_example<G: Game>(state: G) {
     // Compare to MCTS
     println!();
     println!("Comparing with plain MCTS");
-    let config2: RolloutMctsConfig<TicTacToe> = Default::default();
-    let mut node = config2.node_for_new_state(state).0;
+    println!("{}", state);
+    let config2: RolloutMctsConfig<G> = Default::default();
+    let mut tree = Tree::new(state, &config2);
+    let limits = SearchLimits::new()
+        .with_playouts(10_000)
+        .with_deadline(Instant::now() + Duration::from_secs(1))
+        .with_early_stop(true);
+    let result = tree.search(&config2, &limits);
+    println!("{}", result);
+}
+
+fn parallel_mcts_example<G: Game + Send + Sync>(state: G)
+where
+    G::Action: Send,
+{
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    println!();
+    println!("Plain MCTS with {} threads", threads);
+    let config: RolloutMctsConfig<G> = Default::default();
+    let mut tree = Tree::new(state, &config);
+    let start = Instant::now();
+    let result = tree.parallel_search(&config, 10_000, threads);
+    println!("Search took {:?}", start.elapsed());
+    println!("{}", result);
+}
+
+fn transposition_mcts_example<G: Game + StateHash + PartialEq>(state: G) {
+    println!();
+    println!("Plain MCTS with transpositions");
+    println!("{}", state);
+    let config: RolloutMctsConfig<G> = Default::default();
+    let mut graph = SearchGraph::new(state, &config);
     for _ in 0..10_000 {
-        node.walk_to_leaf(&config2);
+        graph.walk_to_leaf(&config);
     }
+    println!("The graph holds {} distinct positions", graph.len());
     // Output the values for all actions:
-    for edge in node.children.iter() {
+    for edge in graph.root().children.iter() {
         println!(
             "Action {:?} has value {:+.4} and was visited {} times. (Prior: {})",
-            edge.action, edge.expected_reward, edge.visit_count, edge.prior_probability
+            edge.action,
+            graph.expected_reward(edge),
+            edge.visit_count,
+            edge.prior_probability
         );
     }
 }
@@ -1,11 +1,38 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+use rand::{distributions::WeightedIndex, prelude::Distribution, Rng};
+use rand_distr::Dirichlet;
+
 use crate::game::Game;
 
+/// Virtual loss that is applied to every edge on a path while the leaf at
+/// its end is being evaluated. This makes the edge look worse to the other
+/// workers, so they spread out over different paths.
+pub const VIRTUAL_LOSS: f32 = 1.0;
+
 // Name sucks, needs to be changed
 pub trait MctsConfigTrait<G: Game> {
     // Returns a new node as well as the estimated value of the node.
     // This new node than already contains all the children with their
     // prior values.
     fn node_for_new_state(&self, state: G) -> (Node<G>, f32);
+
+    // Evaluates several states at once, e.g. in a single forward pass of a
+    // network. The results are in the same order as the states.
+    fn nodes_for_new_states(&self, states: Vec<G>) -> Vec<(Node<G>, f32)> {
+        states
+            .into_iter()
+            .map(|state| self.node_for_new_state(state))
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -32,20 +59,10 @@ impl<G: Game> MctsConfigTrait<G> for RolloutMctsConfig<G> {
 
         let children = actions
             .iter()
-            .map(|action| {
-                Edge::new(
-                    state.clone(),
-                    *action,
-                    prior_probability + 0.001 * rand::random::<f32>(),
-                )
-            })
+            .map(|action| Edge::new(*action, prior_probability + 0.001 * rand::random::<f32>()))
             .collect();
 
-        let node = Node {
-            state,
-            visit_count: 0.0,
-            children,
-        };
+        let node = Node::new(state, children);
 
         let mut state_clone = node.state.clone();
         random_rollout(&mut state_clone);
@@ -55,23 +72,336 @@ impl<G: Game> MctsConfigTrait<G> for RolloutMctsConfig<G> {
     }
 }
 
-/// Implements monte carlo tree search.
+/// Index of a node in a [Tree].
+pub type NodeId = usize;
+
+/// A state together with the edges to all states reachable in one action.
+/// The config returns new nodes with unexpanded edges, which are then
+/// stored in a [Tree].
 pub struct Node<G: Game> {
     pub state: G,
     pub visit_count: f32,
     pub children: Vec<Edge<G>>,
+    /// The game theoretic value of the state, once it is known. Terminal
+    /// states are proven when they are added to a [Tree], all other states
+    /// only by a search with [Tree::with_solver].
+    pub proof: Option<Proof>,
+}
+
+/// A proven result from the perspective of the player to move.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Proof {
+    Win,
+    Loss,
+    Draw,
+}
+
+impl Proof {
+    pub fn value(self) -> f32 {
+        match self {
+            Proof::Win => 1.0,
+            Proof::Loss => -1.0,
+            Proof::Draw => 0.0,
+        }
+    }
+
+    /// The same result from the perspective of the other player.
+    pub fn opponent(self) -> Proof {
+        match self {
+            Proof::Win => Proof::Loss,
+            Proof::Loss => Proof::Win,
+            Proof::Draw => Proof::Draw,
+        }
+    }
 }
 
 pub struct Edge<G: Game> {
-    pub game: G,
     pub action: G::Action,
-    pub node: Option<Node<G>>,
+    /// The node behind this edge in the [Tree], once it was expanded.
+    pub node: Option<NodeId>,
     pub visit_count: f32,
     pub total_value: f32,
     pub expected_reward: f32, // Caches visit_count / total_value
     pub prior_probability: f32,
 }
 
+/// Implements monte carlo tree search.
+///
+/// All nodes are stored in one flat arena and edges refer to their child by
+/// index, so growing the tree doesn't allocate per node and the part that
+/// is still needed after a move can be kept cheaply, see [Tree::advance].
+pub struct Tree<G: Game> {
+    nodes: Vec<Node<G>>,
+    root: NodeId,
+    solver: bool,
+}
+
+/// A leaf that was selected by [Tree::select_leaf] and still needs to be
+/// handed back to [Tree::backup].
+pub struct PendingLeaf<G: Game> {
+    /// The (node, edge index) pairs from the root down to the selected edge.
+    path: Vec<(NodeId, usize)>,
+    /// The state behind an unexpanded edge. This is `None` if the path ends
+    /// in a terminal node, which is scored right away.
+    pub state: Option<G>,
+    terminal_value: f32,
+}
+
+/// Decides when a [Tree::search] stops. At least one of the playouts, the
+/// deadline or the node limit must be set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SearchLimits {
+    /// Maximal number of walks in this search.
+    pub playouts: Option<usize>,
+    pub deadline: Option<Instant>,
+    /// Maximal number of nodes in the tree, to cap its memory. This is not
+    /// enough to end a search on its own, because a small game tree may be
+    /// fully expanded before it is reached.
+    pub max_nodes: Option<usize>,
+    /// Stop as soon as the most visited action can't be overtaken within the
+    /// remaining playouts. This only applies with a playout limit.
+    pub early_stop: bool,
+}
+
+impl SearchLimits {
+    pub fn new() -> Self {
+        Default::default()
+    }
+    pub fn with_playouts(self, playouts: usize) -> Self {
+        SearchLimits {
+            playouts: Some(playouts),
+            ..self
+        }
+    }
+    pub fn with_deadline(self, deadline: Instant) -> Self {
+        SearchLimits {
+            deadline: Some(deadline),
+            ..self
+        }
+    }
+    pub fn with_max_nodes(self, max_nodes: usize) -> Self {
+        SearchLimits {
+            max_nodes: Some(max_nodes),
+            ..self
+        }
+    }
+    pub fn with_early_stop(self, early_stop: bool) -> Self {
+        SearchLimits { early_stop, ..self }
+    }
+}
+
+/// Why a [Tree::search] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Playouts,
+    Deadline,
+    MaxNodes,
+    /// The best action was already certain, see [SearchLimits::early_stop].
+    EarlyStop,
+    /// The root is terminal or was proven by the solver.
+    Proven,
+}
+
+/// How an action is picked from a [SearchResult]. A proven win is always
+/// picked and actions that are proven to lose only if there is no other
+/// choice, whatever the policy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectionPolicy {
+    /// The most visited action. This is the robust default.
+    MaxVisits,
+    /// The action with the highest mean value.
+    MaxValue,
+    /// Samples an action from [SearchResult::visit_distribution] with the
+    /// given temperature, e.g. to explore in self-play.
+    Temperature(f32),
+    /// The action with the highest lower confidence bound of its value,
+    /// `expected_reward - factor / sqrt(visit_count)`, so an action that
+    /// looks good after only a few visits isn't trusted.
+    LowerConfidenceBound(f32),
+}
+
+/// The statistics of one action of the root after a search.
+#[derive(Debug, Clone)]
+pub struct ActionResult<A> {
+    pub action: A,
+    pub visit_count: f32,
+    /// Mean value from the perspective of the player who takes the action.
+    pub expected_reward: f32,
+    pub prior_probability: f32,
+    /// Proven result for the player who takes the action.
+    pub proof: Option<Proof>,
+}
+
+/// What a search found out about the root.
+#[derive(Debug, Clone)]
+pub struct SearchResult<G: Game> {
+    /// The action picked by [SelectionPolicy::MaxVisits], `None` if the root
+    /// is terminal.
+    pub action: Option<G::Action>,
+    pub children: Vec<ActionResult<G::Action>>,
+    /// Mean value of the root from the perspective of the player to move.
+    pub value: f32,
+    /// Number of walks this search made.
+    pub playouts: usize,
+    pub stop_reason: StopReason,
+}
+
+impl<G: Game> SearchResult<G> {
+    /// Picks an action according to the policy. `None` if the root is
+    /// terminal.
+    pub fn select(&self, policy: &SelectionPolicy) -> Option<G::Action> {
+        self.select_with_rng(policy, &mut rand::thread_rng())
+    }
+
+    /// Like [SearchResult::select], but [SelectionPolicy::Temperature]
+    /// samples with the given random number generator.
+    pub fn select_with_rng(
+        &self,
+        policy: &SelectionPolicy,
+        rng: &mut impl Rng,
+    ) -> Option<G::Action> {
+        if let Some(child) = self
+            .children
+            .iter()
+            .find(|child| child.proof == Some(Proof::Win))
+        {
+            return Some(child.action);
+        }
+
+        let not_lost = |child: &&ActionResult<G::Action>| child.proof != Some(Proof::Loss);
+        let candidates: Vec<&ActionResult<G::Action>> =
+            if self.children.iter().any(|c| not_lost(&c)) {
+                self.children.iter().filter(not_lost).collect()
+            } else {
+                self.children.iter().collect()
+            };
+
+        match *policy {
+            SelectionPolicy::MaxVisits => argmax(&candidates, |child| child.visit_count),
+            SelectionPolicy::MaxValue => argmax(&candidates, |child| {
+                if child.visit_count > 0.0 {
+                    child.expected_reward
+                } else {
+                    f32::NEG_INFINITY
+                }
+            }),
+            SelectionPolicy::Temperature(temperature) if temperature > 0.0 => {
+                let distribution = self.visit_distribution(temperature);
+                let weights = self.children.iter().zip(distribution).map(|(child, p)| {
+                    if not_lost(&child) {
+                        p
+                    } else {
+                        0.0
+                    }
+                });
+                match WeightedIndex::new(weights) {
+                    Ok(index) => Some(self.children[index.sample(rng)].action),
+                    // Every action is proven to lose.
+                    Err(_) => argmax(&candidates, |child| child.visit_count),
+                }
+            }
+            SelectionPolicy::Temperature(_) => argmax(&candidates, |child| child.visit_count),
+            SelectionPolicy::LowerConfidenceBound(factor) => argmax(&candidates, |child| {
+                if child.visit_count > 0.0 {
+                    child.expected_reward - factor / child.visit_count.sqrt()
+                } else {
+                    f32::NEG_INFINITY
+                }
+            }),
+        }
+    }
+
+    /// The probability of every child, in the same order, proportional to
+    /// `visit_count^(1 / temperature)` like in AlphaZero. A temperature of
+    /// one gives the visit counts relative to their sum, lower temperatures
+    /// put more weight on the most visited actions, and zero picks the most
+    /// visited one. Unvisited actions get no probability, unless no action
+    /// was visited at all.
+    pub fn visit_distribution(&self, temperature: f32) -> Vec<f32> {
+        // The first of the most visited actions gets all the probability.
+        let most_visited = || {
+            let mut distribution = vec![0.0; self.children.len()];
+            let best = (0..self.children.len()).reduce(|best, i| {
+                if self.children[i].visit_count > self.children[best].visit_count {
+                    i
+                } else {
+                    best
+                }
+            });
+            if let Some(best) = best {
+                distribution[best] = 1.0;
+            }
+            distribution
+        };
+        if temperature <= 0.0 {
+            return most_visited();
+        }
+
+        // This is computed as exp(ln(visit_count) / temperature), minus the
+        // largest exponent, so high powers of the visit counts don't
+        // overflow.
+        let exponents: Vec<f32> = self
+            .children
+            .iter()
+            .map(|child| child.visit_count.ln() / temperature)
+            .collect();
+        let max_exponent = exponents.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        if max_exponent == f32::NEG_INFINITY {
+            let probability = 1.0 / self.children.len() as f32;
+            return vec![probability; self.children.len()];
+        }
+        if max_exponent == f32::INFINITY {
+            return most_visited();
+        }
+
+        let weights: Vec<f32> = exponents
+            .into_iter()
+            .map(|exponent| (exponent - max_exponent).exp())
+            .collect();
+        let sum: f32 = weights.iter().sum();
+        weights.into_iter().map(|weight| weight / sum).collect()
+    }
+}
+
+/// The action with the highest key. If two actions have the same key, the
+/// first one is picked.
+fn argmax<A: Copy>(
+    candidates: &[&ActionResult<A>],
+    key: impl Fn(&ActionResult<A>) -> f32,
+) -> Option<A> {
+    let mut best = None;
+    let mut best_key = f32::NEG_INFINITY;
+    for child in candidates {
+        let child_key = key(child);
+        if best.is_none() || child_key > best_key {
+            best = Some(child.action);
+            best_key = child_key;
+        }
+    }
+    best
+}
+
+impl<G: Game> std::fmt::Display for SearchResult<G> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for child in self.children.iter() {
+            write!(
+                f,
+                "Action {:?} has value {:+.4} and was visited {} times. (Prior: {})",
+                child.action, child.expected_reward, child.visit_count, child.prior_probability
+            )?;
+            match child.proof {
+                Some(proof) => writeln!(f, " Proven {:?}.", proof)?,
+                None => writeln!(f)?,
+            }
+        }
+        write!(
+            f,
+            "Value {:+.4} after {} playouts, stopped by {:?}",
+            self.value, self.playouts, self.stop_reason
+        )
+    }
+}
+
 impl<G: Game> core::fmt::Debug for Node<G> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.state)?;
@@ -92,40 +422,148 @@ impl<G: Game> core::fmt::Debug for Node<G> {
 
 impl<G: Game> core::fmt::Debug for Edge<G> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match &self.node {
-            Some(node) => {
-                writeln!(
-                    f,
-                    "Action {:?}, prior probability {:.4}, visit count {}, total value {}, expected reward {}",
-                    self.action, self.prior_probability, self.visit_count, self.total_value, self.expected_reward
-                )?;
-                format!("{:?}", node)
-                    .split('\n')
-                    .for_each(|line| writeln!(f, "  {}", line).unwrap());
-            }
+        match self.node {
+            Some(node) => write!(
+                f,
+                "Action {:?}, prior probability {:.4}, visit count {}, total value {}, expected reward {}, node {}",
+                self.action, self.prior_probability, self.visit_count, self.total_value, self.expected_reward, node
+            ),
             None => write!(
                 f,
                 "Action {:?}, prior probability {:.4}, unexpanded node.",
                 self.action, self.prior_probability
-            )?,
+            ),
         }
-        Ok(())
+    }
+}
+
+impl<G: Game> core::fmt::Debug for Tree<G> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_node(f, self.root, 0)
     }
 }
 
 impl<G: Game> Node<G> {
+    pub fn new(state: G, children: Vec<Edge<G>>) -> Node<G> {
+        Node {
+            state,
+            visit_count: 0.0,
+            children,
+            proof: None,
+        }
+    }
+}
+
+impl<G: Game> Tree<G> {
+    /// Starts a new tree with the state as its root.
+    pub fn new(state: G, config: &impl MctsConfigTrait<G>) -> Self {
+        let mut tree = Tree {
+            nodes: Vec::new(),
+            root: 0,
+            solver: false,
+        };
+        tree.root = tree.push(config.node_for_new_state(state).0);
+        tree
+    }
+
+    /// Enables the MCTS-Solver: proven results are propagated up the tree,
+    /// the search doesn't descend below proven nodes any more and always
+    /// takes a proven win.
+    pub fn with_solver(self, solver: bool) -> Self {
+        Tree { solver, ..self }
+    }
+
+    pub fn root(&self) -> &Node<G> {
+        &self.nodes[self.root]
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node<G> {
+        &self.nodes[id]
+    }
+
+    /// Number of nodes in the tree.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn push(&mut self, mut node: Node<G>) -> NodeId {
+        if node.state.get_victory_state().is_terminal() {
+            let player = node.state.get_player();
+            node.proof = Some(match score_terminal_victory_state(&node.state, player) {
+                value if value > 0.0 => Proof::Win,
+                value if value < 0.0 => Proof::Loss,
+                _ => Proof::Draw,
+            });
+        }
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    /// The value of a node the search stops at: a terminal node, or in
+    /// solver mode any proven node.
+    fn leaf_value(&self, node: &Node<G>) -> Option<f32> {
+        if node.children.is_empty() {
+            return Some(score_terminal_victory_state(
+                &node.state,
+                node.state.get_player(),
+            ));
+        }
+        if self.solver {
+            return node.proof.map(Proof::value);
+        }
+        None
+    }
+
+    /// In solver mode, the exact value of a proven node replaces its
+    /// evaluation.
+    fn proven_value(&self, id: NodeId) -> Option<f32> {
+        if self.solver {
+            self.nodes[id].proof.map(Proof::value)
+        } else {
+            None
+        }
+    }
+
+    /// The proof of the node behind an edge, from the perspective of the
+    /// player who moves there.
+    fn child_proof(&self, edge: &Edge<G>) -> Option<Proof> {
+        edge.node.and_then(|child| self.nodes[child].proof)
+    }
+
     /// Choose an action that maximizes Q+U.
-    fn choose_edge_index(&self) -> usize {
+    fn choose_edge_index(&self, id: NodeId) -> usize {
+        let node = &self.nodes[id];
+
+        if self.solver {
+            // If the opponent is lost after an action, there is nothing left
+            // to search.
+            if let Some(i) = node
+                .children
+                .iter()
+                .position(|edge| self.child_proof(edge) == Some(Proof::Loss))
+            {
+                return i;
+            }
+        }
+
         let mut best_action_index = 0;
-        let mut best_action_value = -std::f32::INFINITY;
+        let mut best_action_value = f32::NEG_INFINITY;
 
-        for (i, action) in self.children.iter().enumerate() {
+        for (i, action) in node.children.iter().enumerate() {
+            // Actions that are proven to lose are never worth searching.
+            if self.solver && self.child_proof(action) == Some(Proof::Win) {
+                continue;
+            }
             let expected_reward = action.expected_reward;
             // We add + 0.0001 so the policy is already respected in the first
             // step.
-            let explore_value = self.state.exploration_factor()
+            let explore_value = node.state.exploration_factor()
                 * action.prior_probability
-                * ((self.visit_count).sqrt() / (1.0 + action.visit_count) + 0.0001);
+                * ((node.visit_count).sqrt() / (1.0 + action.visit_count) + 0.0001);
             let action_value = expected_reward + explore_value;
             // Note that if two actions have the same value, we always pick
             // the first one.
@@ -138,34 +576,445 @@ impl<G: Game> Node<G> {
         best_action_index
     }
 
-    pub fn walk_to_leaf(&mut self, config: &impl MctsConfigTrait<G>) -> f32 {
-        if self.children.is_empty() {
-            return score_terminal_victory_state(&self.state, self.state.get_player());
+    /// Proves a node from its children: it is won if one action leads to a
+    /// position that is lost for the opponent. Once every child is proven,
+    /// it is drawn if one of them is a draw and lost otherwise.
+    fn update_proof(&mut self, id: NodeId) {
+        let node = &self.nodes[id];
+        if node.proof.is_some() {
+            return;
+        }
+
+        let mut proof = Some(Proof::Loss);
+        for edge in node.children.iter() {
+            match self.child_proof(edge) {
+                Some(Proof::Loss) => {
+                    proof = Some(Proof::Win);
+                    break;
+                }
+                Some(Proof::Draw) => proof = proof.map(|_| Proof::Draw),
+                Some(Proof::Win) => {}
+                None => proof = None,
+            }
         }
+        self.nodes[id].proof = proof;
+    }
 
-        let edge_index = self.choose_edge_index();
-        let edge = &mut self.children[edge_index];
+    /// Walks the tree until one of the limits is reached. Visits from
+    /// earlier searches of the same tree count for the result, but not
+    /// towards the playout limit.
+    ///
+    /// Panics, if there is neither a playout limit nor a deadline.
+    pub fn search(
+        &mut self,
+        config: &impl MctsConfigTrait<G>,
+        limits: &SearchLimits,
+    ) -> SearchResult<G> {
+        assert!(
+            limits.playouts.is_some() || limits.deadline.is_some(),
+            "A search without a playout limit or a deadline may never stop"
+        );
 
-        let value = if let Some(ref mut child_node) = edge.node {
-            // Here we assume alternating players.
-            -child_node.walk_to_leaf(config)
-        } else {
-            let mut new_state = self.state.clone();
-            new_state.apply_action(edge.action);
-            let (new_node, value) = config.node_for_new_state(new_state);
+        let mut playouts = 0;
+        let stop_reason = loop {
+            if self.root().proof.is_some() {
+                break StopReason::Proven;
+            }
+            if let Some(max_playouts) = limits.playouts {
+                if playouts >= max_playouts {
+                    break StopReason::Playouts;
+                }
+                if limits.early_stop && self.is_decided(max_playouts - playouts) {
+                    break StopReason::EarlyStop;
+                }
+            }
+            if limits
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                break StopReason::Deadline;
+            }
+            if limits
+                .max_nodes
+                .is_some_and(|max_nodes| self.len() >= max_nodes)
+            {
+                break StopReason::MaxNodes;
+            }
+
+            self.walk_to_leaf(config);
+            playouts += 1;
+        };
+
+        self.search_result(playouts, stop_reason)
+    }
+
+    /// Whether the most visited action of the root stays ahead, even if all
+    /// remaining playouts go to the second most visited one.
+    fn is_decided(&self, remaining: usize) -> bool {
+        let mut best = 0.0;
+        let mut second = 0.0;
+        for edge in self.root().children.iter() {
+            if edge.visit_count > best {
+                second = best;
+                best = edge.visit_count;
+            } else if edge.visit_count > second {
+                second = edge.visit_count;
+            }
+        }
+        best - second > remaining as f32
+    }
+
+    fn search_result(&self, playouts: usize, stop_reason: StopReason) -> SearchResult<G> {
+        let root = self.root();
+        let value = match root.proof {
+            Some(proof) => proof.value(),
+            None if root.visit_count > 0.0 => {
+                // The edge values are from the perspective of the player at
+                // the root.
+                root.children
+                    .iter()
+                    .map(|edge| edge.total_value)
+                    .sum::<f32>()
+                    / root.visit_count
+            }
+            None => 0.0,
+        };
+
+        let children = root
+            .children
+            .iter()
+            .map(|edge| ActionResult {
+                action: edge.action,
+                visit_count: edge.visit_count,
+                expected_reward: edge.expected_reward,
+                prior_probability: edge.prior_probability,
+                proof: self.child_proof(edge).map(Proof::opponent),
+            })
+            .collect();
 
-            edge.node = Some(new_node);
-            -value
+        let mut result = SearchResult {
+            action: None,
+            children,
+            value,
+            playouts,
+            stop_reason,
         };
+        result.action = result.select(&SelectionPolicy::MaxVisits);
+        result
+    }
+
+    fn fmt_node(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        id: NodeId,
+        depth: usize,
+    ) -> std::fmt::Result {
+        let indent = "  ".repeat(depth);
+        let node = &self.nodes[id];
+        for line in format!("{}", node.state).lines() {
+            writeln!(f, "{}{}", indent, line)?;
+        }
+        writeln!(
+            f,
+            "{}This node has {} children with a total of {} visits",
+            indent,
+            node.children.len(),
+            node.visit_count,
+        )?;
+        if let Some(proof) = node.proof {
+            writeln!(f, "{}It is a proven {:?}", indent, proof)?;
+        }
+        for edge in node.children.iter() {
+            writeln!(f, "{}{:?}", indent, edge)?;
+            if let Some(child) = edge.node {
+                self.fmt_node(f, child, depth + 1)?;
+            }
+        }
+        Ok(())
+    }
 
-        edge.total_value += value;
-        edge.visit_count += 1.0;
-        edge.expected_reward = edge.total_value / edge.visit_count;
+    /// Descends from the root to an unexpanded edge or a terminal node,
+    /// evaluates it and propagates the value back up. Returns the value from
+    /// the perspective of the player at the root.
+    pub fn walk_to_leaf(&mut self, config: &impl MctsConfigTrait<G>) -> f32 {
+        let mut path = Vec::new();
+        let mut current = self.root;
+
+        let leaf_value = loop {
+            let node = &self.nodes[current];
+            if let Some(value) = self.leaf_value(node) {
+                break value;
+            }
 
-        self.visit_count += 1.0;
+            let edge_index = self.choose_edge_index(current);
+            path.push((current, edge_index));
+
+            let edge = &node.children[edge_index];
+            match edge.node {
+                Some(child) => current = child,
+                None => {
+                    let mut new_state = node.state.clone();
+                    new_state.apply_action(edge.action);
+                    let (new_node, value) = config.node_for_new_state(new_state);
+
+                    let child = self.push(new_node);
+                    self.nodes[current].children[edge_index].node = Some(child);
+                    break self.proven_value(child).unwrap_or(value);
+                }
+            }
+        };
+
+        let mut value = leaf_value;
+        for &(id, edge_index) in path.iter().rev() {
+            // Here we assume alternating players.
+            value = -value;
+            let node = &mut self.nodes[id];
+            let edge = &mut node.children[edge_index];
+            edge.total_value += value;
+            edge.visit_count += 1.0;
+            edge.expected_reward = edge.total_value / edge.visit_count;
+            node.visit_count += 1.0;
+            if self.solver {
+                self.update_proof(id);
+            }
+        }
 
         value
     }
+
+    /// Descends the tree like [Tree::walk_to_leaf], but stops at the first
+    /// unexpanded edge instead of evaluating it. Virtual loss is applied to
+    /// every edge on the way, which must be reverted by passing the returned
+    /// leaf to [Tree::backup].
+    pub fn select_leaf(&mut self) -> PendingLeaf<G> {
+        let mut path = Vec::new();
+        let mut current = self.root;
+
+        loop {
+            if let Some(terminal_value) = self.leaf_value(&self.nodes[current]) {
+                return PendingLeaf {
+                    path,
+                    state: None,
+                    terminal_value,
+                };
+            }
+
+            let edge_index = self.choose_edge_index(current);
+            path.push((current, edge_index));
+            let node = &mut self.nodes[current];
+            node.visit_count += 1.0;
+
+            let edge = &mut node.children[edge_index];
+            edge.visit_count += 1.0;
+            edge.total_value -= VIRTUAL_LOSS;
+            edge.expected_reward = edge.total_value / edge.visit_count;
+
+            match edge.node {
+                Some(child) => current = child,
+                None => {
+                    let action = edge.action;
+                    let mut new_state = node.state.clone();
+                    new_state.apply_action(action);
+                    return PendingLeaf {
+                        path,
+                        state: Some(new_state),
+                        terminal_value: 0.0,
+                    };
+                }
+            }
+        }
+    }
+
+    /// Reverts the virtual loss of a leaf returned by [Tree::select_leaf] and
+    /// propagates its value up to the root. For an unexpanded leaf, the
+    /// evaluation is the result of `node_for_new_state` on its state.
+    ///
+    /// If another worker expanded the same edge in the meantime, the new node
+    /// is dropped and only its value is counted.
+    pub fn backup(&mut self, leaf: PendingLeaf<G>, evaluation: Option<(Node<G>, f32)>) -> f32 {
+        let leaf_value = match evaluation {
+            Some((new_node, value)) => {
+                let &(parent, edge_index) = leaf
+                    .path
+                    .last()
+                    .expect("Only unexpanded edges are evaluated");
+                match self.nodes[parent].children[edge_index].node {
+                    Some(child) => self.proven_value(child).unwrap_or(value),
+                    None => {
+                        let child = self.push(new_node);
+                        self.nodes[parent].children[edge_index].node = Some(child);
+                        self.proven_value(child).unwrap_or(value)
+                    }
+                }
+            }
+            None => leaf.terminal_value,
+        };
+
+        let mut value = leaf_value;
+        for &(id, edge_index) in leaf.path.iter().rev() {
+            // Here we assume alternating players.
+            value = -value;
+            // The visit was already counted during the selection.
+            let edge = &mut self.nodes[id].children[edge_index];
+            edge.total_value += VIRTUAL_LOSS + value;
+            edge.expected_reward = edge.total_value / edge.visit_count;
+            if self.solver {
+                self.update_proof(id);
+            }
+        }
+
+        value
+    }
+
+    /// Runs `playouts` walks with several worker threads descending the same
+    /// tree. The tree is locked while a worker selects a leaf or backs it up,
+    /// but the expensive evaluation of the leaf happens in parallel.
+    ///
+    /// The network config can't be shared between threads, because dfdx
+    /// tensors are reference counted, so this is mostly useful for rollouts.
+    pub fn parallel_search<C>(
+        &mut self,
+        config: &C,
+        playouts: usize,
+        threads: usize,
+    ) -> SearchResult<G>
+    where
+        C: MctsConfigTrait<G> + Sync,
+        G: Send,
+        G::Action: Send,
+    {
+        let tree = Mutex::new(&mut *self);
+        let remaining = AtomicUsize::new(playouts);
+
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| {
+                    while remaining
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |r| r.checked_sub(1))
+                        .is_ok()
+                    {
+                        let leaf = tree.lock().unwrap().select_leaf();
+                        let evaluation = leaf
+                            .state
+                            .clone()
+                            .map(|state| config.node_for_new_state(state));
+                        tree.lock().unwrap().backup(leaf, evaluation);
+                    }
+                });
+            }
+        });
+
+        self.search_result(playouts, StopReason::Playouts)
+    }
+
+    /// Runs `playouts` walks, but instead of evaluating every leaf on its
+    /// own, up to `batch_size` leaves are selected with virtual loss and then
+    /// evaluated together by [MctsConfigTrait::nodes_for_new_states].
+    ///
+    /// Panics, if `batch_size` is zero.
+    pub fn batched_search(
+        &mut self,
+        config: &impl MctsConfigTrait<G>,
+        playouts: usize,
+        batch_size: usize,
+    ) -> SearchResult<G> {
+        assert!(batch_size > 0, "A search with empty batches never stops");
+
+        let mut remaining = playouts;
+        while remaining > 0 {
+            let count = batch_size.min(remaining);
+            let leaves: Vec<PendingLeaf<G>> = (0..count).map(|_| self.select_leaf()).collect();
+
+            let states = leaves
+                .iter()
+                .filter_map(|leaf| leaf.state.clone())
+                .collect();
+            let mut evaluations = config.nodes_for_new_states(states).into_iter();
+
+            for leaf in leaves {
+                let evaluation = match leaf.state {
+                    Some(_) => evaluations.next(),
+                    None => None,
+                };
+                self.backup(leaf, evaluation);
+            }
+            remaining -= count;
+        }
+
+        self.search_result(playouts, StopReason::Playouts)
+    }
+
+    /// Mixes Dirichlet noise into the prior probabilities of the children of
+    /// the root, so self-play explores moves the network doesn't like yet.
+    ///
+    /// `weight` is the share of the noise in the new prior, zero disables it.
+    pub fn add_dirichlet_noise(&mut self, alpha: f32, weight: f32, rng: &mut impl Rng) {
+        let root = &mut self.nodes[self.root];
+        if weight <= 0.0 || root.children.len() < 2 {
+            return;
+        }
+
+        let dirichlet = Dirichlet::new_with_size(alpha, root.children.len())
+            .expect("Dirichlet alpha must be positive");
+        let noise = dirichlet.sample(rng);
+
+        for (edge, noise) in root.children.iter_mut().zip(noise) {
+            edge.prior_probability = (1.0 - weight) * edge.prior_probability + weight * noise;
+        }
+    }
+
+    /// Advances the root along a played action. The subtree below the
+    /// matching edge becomes the new tree and all siblings are discarded, so
+    /// the visits accumulated under the chosen action are kept. If that edge
+    /// was never expanded, a fresh tree is created from the config.
+    ///
+    /// Panics, if the action is not a child of the root.
+    pub fn advance(self, action: G::Action, config: &impl MctsConfigTrait<G>) -> Tree<G> {
+        let root = self.root();
+        let edge = root
+            .children
+            .iter()
+            .find(|edge| edge.action == action)
+            .unwrap_or_else(|| panic!("Action {:?} is not a child of this node", action));
+
+        match edge.node {
+            Some(child) => self.subtree(child),
+            None => {
+                let mut new_state = root.state.clone();
+                new_state.apply_action(action);
+                Tree::new(new_state, config).with_solver(self.solver)
+            }
+        }
+    }
+
+    /// Moves the nodes below `root` into a new, compact arena and drops all
+    /// other nodes.
+    fn subtree(self, root: NodeId) -> Tree<G> {
+        let mut old_nodes: Vec<Option<Node<G>>> = self.nodes.into_iter().map(Some).collect();
+        let mut nodes = Vec::new();
+
+        // Breadth first, so the new index of a node is known as soon as it
+        // is queued: everything before it in the queue is pushed first.
+        let mut queue = VecDeque::from([root]);
+        while let Some(old_id) = queue.pop_front() {
+            let mut node = old_nodes[old_id]
+                .take()
+                .expect("Every node has only one parent");
+            for edge in node.children.iter_mut() {
+                if let Some(child) = edge.node {
+                    edge.node = Some(nodes.len() + 1 + queue.len());
+                    queue.push_back(child);
+                }
+            }
+            nodes.push(node);
+        }
+
+        Tree {
+            nodes,
+            root: 0,
+            solver: self.solver,
+        }
+    }
 }
 
 fn random_rollout<G: Game>(state: &mut G) {
@@ -178,7 +1027,7 @@ fn random_rollout<G: Game>(state: &mut G) {
 
 /// Returns a score for a terminal state. Panics, if the state is not
 /// terminal.
-fn score_terminal_victory_state(state: &impl Game, player: crate::game::Player) -> f32 {
+pub fn score_terminal_victory_state(state: &impl Game, player: crate::game::Player) -> f32 {
     match state.get_victory_state() {
         crate::game::VictoryState::InProgress => panic!("Game should be over"),
         crate::game::VictoryState::Draw => 0.0,
@@ -193,9 +1042,8 @@ fn score_terminal_victory_state(state: &impl Game, player: crate::game::Player)
 }
 
 impl<G: Game> Edge<G> {
-    pub fn new(game: G, action: G::Action, prior_probability: f32) -> Edge<G> {
+    pub fn new(action: G::Action, prior_probability: f32) -> Edge<G> {
         Edge {
-            game,
             action,
             node: None,
             visit_count: 0.0,
@@ -205,3 +1053,367 @@ impl<G: Game> Edge<G> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::tictactoe::TicTacToe;
+
+    const POLICIES: [SelectionPolicy; 5] = [
+        SelectionPolicy::MaxVisits,
+        SelectionPolicy::MaxValue,
+        SelectionPolicy::Temperature(1.0),
+        SelectionPolicy::Temperature(0.0),
+        SelectionPolicy::LowerConfidenceBound(1.0),
+    ];
+
+    /// A result for children given as (visit count, expected reward, proof).
+    /// The action of the i-th child is (i, 0).
+    fn result(children: &[(f32, f32, Option<Proof>)]) -> SearchResult<TicTacToe> {
+        SearchResult {
+            action: None,
+            children: children
+                .iter()
+                .enumerate()
+                .map(|(i, &(visit_count, expected_reward, proof))| ActionResult {
+                    action: (i, 0),
+                    visit_count,
+                    expected_reward,
+                    prior_probability: 1.0 / children.len() as f32,
+                    proof,
+                })
+                .collect(),
+            value: 0.0,
+            playouts: 0,
+            stop_reason: StopReason::Playouts,
+        }
+    }
+
+    /// Selects many times, so sampling policies have a chance to go wrong.
+    fn selections(
+        result: &SearchResult<TicTacToe>,
+        policy: &SelectionPolicy,
+    ) -> Vec<(usize, usize)> {
+        let mut rng = StdRng::seed_from_u64(0);
+        (0..100)
+            .map(|_| result.select_with_rng(policy, &mut rng).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn proven_win_is_always_picked() {
+        let result = result(&[
+            (90.0, 0.5, None),
+            (2.0, 1.0, Some(Proof::Win)),
+            (8.0, 0.2, None),
+        ]);
+        for policy in &POLICIES {
+            assert!(selections(&result, policy)
+                .iter()
+                .all(|&action| action == (1, 0)));
+        }
+    }
+
+    #[test]
+    fn proven_losses_are_skipped() {
+        let result = result(&[(90.0, 0.9, Some(Proof::Loss)), (10.0, 0.1, None)]);
+        for policy in &POLICIES {
+            assert!(selections(&result, policy)
+                .iter()
+                .all(|&action| action == (1, 0)));
+        }
+    }
+
+    #[test]
+    fn proven_losses_are_picked_if_all_actions_lose() {
+        let result = result(&[
+            (10.0, -1.0, Some(Proof::Loss)),
+            (90.0, -1.0, Some(Proof::Loss)),
+        ]);
+        for policy in &POLICIES {
+            assert!(result.select(policy).is_some());
+        }
+        assert_eq!(result.select(&SelectionPolicy::MaxVisits), Some((1, 0)));
+    }
+
+    #[test]
+    fn unvisited_actions_are_not_picked_by_value() {
+        // The unvisited action has the highest expected reward, because it
+        // was never updated. The lower confidence bound doesn't trust the
+        // two visits of the second action.
+        let result = result(&[(0.0, 0.0, None), (2.0, -0.2, None), (40.0, -0.5, None)]);
+        assert_eq!(result.select(&SelectionPolicy::MaxValue), Some((1, 0)));
+        assert_eq!(
+            result.select(&SelectionPolicy::LowerConfidenceBound(1.0)),
+            Some((2, 0))
+        );
+    }
+
+    #[test]
+    fn zero_temperature_picks_max_visits() {
+        let result = result(&[(10.0, 0.9, None), (60.0, 0.1, None), (30.0, 0.5, None)]);
+        assert_eq!(
+            selections(&result, &SelectionPolicy::Temperature(0.0)),
+            vec![(1, 0); 100]
+        );
+        assert_eq!(result.visit_distribution(0.0), vec![0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn visit_distribution_follows_visit_counts() {
+        let result = result(&[
+            (150.0, 0.0, None),
+            (10.0, 0.0, None),
+            (0.0, 0.0, None),
+            (840.0, 0.0, None),
+        ]);
+        let expected = [0.15, 0.01, 0.0, 0.84];
+        for (p, e) in result.visit_distribution(1.0).into_iter().zip(expected) {
+            assert!((p - e).abs() < 1e-6);
+        }
+
+        // Squaring the visit counts for half the temperature.
+        let distribution = result.visit_distribution(0.5);
+        let sum = 150.0f32.powi(2) + 10.0f32.powi(2) + 840.0f32.powi(2);
+        assert!((distribution[0] - 150.0f32.powi(2) / sum).abs() < 1e-6);
+    }
+
+    fn searched_tree(playouts: usize) -> Tree<TicTacToe> {
+        let config: RolloutMctsConfig<TicTacToe> = Default::default();
+        let mut tree = Tree::new(TicTacToe::new(), &config);
+        tree.search(&config, &SearchLimits::new().with_playouts(playouts));
+        tree
+    }
+
+    #[test]
+    fn advance_keeps_the_subtree_of_an_expanded_edge() {
+        let config: RolloutMctsConfig<TicTacToe> = Default::default();
+        let tree = searched_tree(200);
+        let edge = tree
+            .root()
+            .children
+            .iter()
+            .find(|edge| edge.node.is_some())
+            .unwrap();
+        let child = tree.node(edge.node.unwrap());
+        let (action, state, visit_count, children) = (
+            edge.action,
+            child.state.clone(),
+            child.visit_count,
+            child.children.len(),
+        );
+        let old_len = tree.len();
+
+        let tree = tree.advance(action, &config);
+        assert!(tree.root().state == state);
+        assert_eq!(tree.root().visit_count, visit_count);
+        assert_eq!(tree.root().children.len(), children);
+        assert!(tree.len() < old_len);
+    }
+
+    #[test]
+    fn advance_along_an_unexpanded_edge_starts_a_new_tree() {
+        let config: RolloutMctsConfig<TicTacToe> = Default::default();
+        let tree = searched_tree(2);
+        let action = tree
+            .root()
+            .children
+            .iter()
+            .find(|edge| edge.node.is_none())
+            .unwrap()
+            .action;
+        let mut state = tree.root().state.clone();
+        state.apply_action(action);
+
+        let tree = tree.advance(action, &config);
+        assert!(tree.root().state == state);
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.root().visit_count, 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a child")]
+    fn advance_panics_on_an_unknown_action() {
+        let config: RolloutMctsConfig<TicTacToe> = Default::default();
+        let mut state = TicTacToe::new();
+        state.apply_action((1, 1));
+        Tree::new(state, &config).advance((1, 1), &config);
+    }
+
+    /// Checks that every edge points at the state its action leads to, that
+    /// the visits of a node match its edge, and that every node of the arena
+    /// is reachable from the root.
+    fn assert_consistent(tree: &Tree<TicTacToe>) {
+        let mut reachable = 0;
+        let mut stack = vec![tree.root];
+        while let Some(id) = stack.pop() {
+            reachable += 1;
+            let node = tree.node(id);
+            for edge in &node.children {
+                if let Some(child) = edge.node {
+                    let mut state = node.state.clone();
+                    state.apply_action(edge.action);
+                    assert!(tree.node(child).state == state);
+                    if !state.get_victory_state().is_terminal() {
+                        assert_eq!(tree.node(child).visit_count + 1.0, edge.visit_count);
+                    }
+                    stack.push(child);
+                }
+            }
+        }
+        assert_eq!(reachable, tree.len());
+    }
+
+    #[test]
+    fn advance_compacts_the_subtree() {
+        let config: RolloutMctsConfig<TicTacToe> = Default::default();
+        let mut tree = searched_tree(2000);
+        assert_consistent(&tree);
+
+        // Twice, so the second advance starts from a compacted arena.
+        for _ in 0..2 {
+            let edge = tree
+                .root()
+                .children
+                .iter()
+                .max_by(|a, b| a.visit_count.total_cmp(&b.visit_count))
+                .unwrap();
+            let (action, visit_count) = (edge.action, edge.visit_count);
+
+            tree = tree.advance(action, &config);
+            // The walk that expanded the node only counts for its edge.
+            assert_eq!(tree.root().visit_count + 1.0, visit_count);
+            assert_consistent(&tree);
+        }
+    }
+
+    /// Searches the position after `actions` with the solver.
+    fn solved_tree(actions: &[(usize, usize)]) -> (Tree<TicTacToe>, SearchResult<TicTacToe>) {
+        let mut state = TicTacToe::new();
+        for &action in actions {
+            state.apply_action(action);
+        }
+        let config: RolloutMctsConfig<TicTacToe> = Default::default();
+        let mut tree = Tree::new(state, &config).with_solver(true);
+        let result = tree.search(&config, &SearchLimits::new().with_playouts(10_000));
+        (tree, result)
+    }
+
+    #[test]
+    fn solver_proves_a_win_in_one() {
+        // X to move completes the top row.
+        let (tree, result) = solved_tree(&[(0, 0), (1, 1), (0, 1), (2, 2)]);
+        assert_eq!(tree.root().proof, Some(Proof::Win));
+        assert_eq!(result.action, Some((0, 2)));
+    }
+
+    #[test]
+    fn solver_proves_a_draw_when_no_child_wins() {
+        // O to move must block the bottom row, which fills the board
+        // without a winner. The other free cell loses.
+        let (tree, result) = solved_tree(&[(0, 0), (1, 1), (2, 2), (0, 2), (2, 0), (1, 0), (1, 2)]);
+        assert_eq!(tree.root().proof, Some(Proof::Draw));
+        assert_eq!(result.action, Some((2, 1)));
+        for child in &result.children {
+            let expected = if child.action == (2, 1) {
+                Proof::Draw
+            } else {
+                Proof::Loss
+            };
+            assert_eq!(child.proof, Some(expected), "for {:?}", child.action);
+        }
+    }
+
+    #[test]
+    fn solver_proves_a_loss_when_every_child_loses() {
+        // X threatens both the left column and the bottom row.
+        let (tree, result) = solved_tree(&[(0, 0), (1, 1), (2, 2), (0, 2), (2, 0)]);
+        assert_eq!(tree.root().proof, Some(Proof::Loss));
+        assert_eq!(result.children.len(), 4);
+        assert!(result
+            .children
+            .iter()
+            .all(|child| child.proof == Some(Proof::Loss)));
+        // A move is still returned, even though the game is lost.
+        assert!(result.action.is_some());
+    }
+
+    /// Searches the position after `actions` without the solver.
+    fn search_with_limits(
+        actions: &[(usize, usize)],
+        limits: &SearchLimits,
+    ) -> (Tree<TicTacToe>, SearchResult<TicTacToe>) {
+        let mut state = TicTacToe::new();
+        for &action in actions {
+            state.apply_action(action);
+        }
+        let config: RolloutMctsConfig<TicTacToe> = Default::default();
+        let mut tree = Tree::new(state, &config);
+        let result = tree.search(&config, limits);
+        (tree, result)
+    }
+
+    #[test]
+    fn search_stops_after_the_playouts() {
+        let (_, result) = search_with_limits(&[], &SearchLimits::new().with_playouts(100));
+        assert_eq!(result.stop_reason, StopReason::Playouts);
+        assert_eq!(result.playouts, 100);
+    }
+
+    #[test]
+    fn search_stops_at_the_deadline() {
+        let deadline = Instant::now() + Duration::from_millis(20);
+        let (_, result) = search_with_limits(&[], &SearchLimits::new().with_deadline(deadline));
+        assert_eq!(result.stop_reason, StopReason::Deadline);
+        assert!(Instant::now() >= deadline);
+    }
+
+    #[test]
+    fn search_stops_at_the_node_limit() {
+        let limits = SearchLimits::new()
+            .with_playouts(100_000)
+            .with_max_nodes(50);
+        let (tree, result) = search_with_limits(&[], &limits);
+        assert_eq!(result.stop_reason, StopReason::MaxNodes);
+        assert!(tree.len() >= 50);
+        assert!(result.playouts < 100_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "may never stop")]
+    fn search_with_only_a_node_limit_panics() {
+        // The whole game tree of this position has fewer than 50 nodes.
+        let actions = [(0, 0), (1, 1), (2, 2), (0, 2), (2, 0)];
+        search_with_limits(&actions, &SearchLimits::new().with_max_nodes(50));
+    }
+
+    #[test]
+    fn search_stops_early_when_the_best_action_is_certain() {
+        // X to move completes the top row, which soon dominates the visits.
+        let limits = SearchLimits::new()
+            .with_playouts(10_000)
+            .with_early_stop(true);
+        let (_, result) = search_with_limits(&[(0, 0), (1, 1), (0, 1), (2, 2)], &limits);
+        assert_eq!(result.stop_reason, StopReason::EarlyStop);
+        assert!(result.playouts < 10_000);
+        assert_eq!(result.action, Some((0, 2)));
+    }
+
+    #[test]
+    fn search_stops_when_the_root_is_proven() {
+        let (_, result) = solved_tree(&[(0, 0), (1, 1), (0, 1), (2, 2)]);
+        assert_eq!(result.stop_reason, StopReason::Proven);
+        assert!(result.playouts < 10_000);
+
+        // A terminal root is proven without the solver.
+        let limits = SearchLimits::new().with_playouts(100);
+        let (_, result) = search_with_limits(&[(0, 0), (1, 1), (0, 1), (2, 2), (0, 2)], &limits);
+        assert_eq!(result.stop_reason, StopReason::Proven);
+        assert_eq!(result.playouts, 0);
+        assert_eq!(result.action, None);
+    }
+}
@@ -0,0 +1,334 @@
+use std::fmt::Display;
+
+use dfdx::tensor::{Tensor1D, TensorCreator};
+
+use crate::{
+    game::{Game, Player, SquareSymmetry, VictoryState},
+    nn::NetworkGame,
+};
+
+/// Implements Ultimate TicTacToe, also known as MetaTac.
+///
+/// The 9x9 board is made up of nine TicTacToe sub-boards. The cell a player
+/// picks inside a sub-board determines the sub-board the opponent has to play
+/// in next. If that sub-board is already won or full, the opponent may play
+/// in any open sub-board. Winning three sub-boards in a row wins the game.
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct MetaTac {
+    /// Indexed by `[x][y]` in global coordinates from 0 to 8.
+    pub board: [[Option<Player>; 9]; 9],
+    pub current_player: Player,
+    /// The sub-board the current player has to play in, if restricted.
+    pub focus: Option<usize>,
+    /// Winners of the sub-boards, indexed by `sub_x + 3 * sub_y`.
+    sub_winners: [Option<Player>; 9],
+    winner: Option<Player>,
+}
+
+/// Global (x, y) coordinates of the cell to play.
+type Action = (usize, usize);
+
+fn sub_board_index((x, y): Action) -> usize {
+    x / 3 + 3 * (y / 3)
+}
+
+/// Returns the player that owns three cells in a line, if any.
+fn line_winner(cells: [[Option<Player>; 3]; 3]) -> Option<Player> {
+    let lines = [
+        [(0, 0), (0, 1), (0, 2)],
+        [(1, 0), (1, 1), (1, 2)],
+        [(2, 0), (2, 1), (2, 2)],
+        [(0, 0), (1, 0), (2, 0)],
+        [(0, 1), (1, 1), (2, 1)],
+        [(0, 2), (1, 2), (2, 2)],
+        [(0, 0), (1, 1), (2, 2)],
+        [(0, 2), (1, 1), (2, 0)],
+    ];
+
+    lines.iter().find_map(|[a, b, c]| {
+        let first = cells[a.0][a.1];
+        if first.is_some() && first == cells[b.0][b.1] && first == cells[c.0][c.1] {
+            first
+        } else {
+            None
+        }
+    })
+}
+
+impl MetaTac {
+    pub fn new() -> MetaTac {
+        MetaTac {
+            board: [[None; 9]; 9],
+            current_player: Player::X,
+            focus: None,
+            sub_winners: [None; 9],
+            winner: None,
+        }
+    }
+
+    /// Cells of a sub-board, indexed by local `[x][y]`.
+    fn sub_board(&self, index: usize) -> [[Option<Player>; 3]; 3] {
+        let (offset_x, offset_y) = (3 * (index % 3), 3 * (index / 3));
+        let mut cells = [[None; 3]; 3];
+        for (x, column) in cells.iter_mut().enumerate() {
+            for (y, cell) in column.iter_mut().enumerate() {
+                *cell = self.board[offset_x + x][offset_y + y];
+            }
+        }
+        cells
+    }
+
+    /// A sub-board is closed if it is won or has no free cell left.
+    fn is_closed(&self, index: usize) -> bool {
+        self.sub_winners[index].is_some()
+            || self
+                .sub_board(index)
+                .iter()
+                .flatten()
+                .all(|cell| cell.is_some())
+    }
+
+    fn play(&mut self, player: Player, (x, y): Action) -> bool {
+        let index = sub_board_index((x, y));
+        if self.board[x][y].is_some() || self.is_closed(index) {
+            return false;
+        }
+
+        self.board[x][y] = Some(player);
+
+        if let Some(sub_winner) = line_winner(self.sub_board(index)) {
+            self.sub_winners[index] = Some(sub_winner);
+
+            let mut meta_board = [[None; 3]; 3];
+            for (i, sub_winner) in self.sub_winners.iter().enumerate() {
+                meta_board[i % 3][i / 3] = *sub_winner;
+            }
+            self.winner = line_winner(meta_board);
+        }
+
+        // The opponent is sent to the sub-board matching the local cell.
+        let target = x % 3 + 3 * (y % 3);
+        self.focus = if self.is_closed(target) {
+            None
+        } else {
+            Some(target)
+        };
+        true
+    }
+}
+
+impl Default for MetaTac {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for MetaTac {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for i in 0..9 {
+            if i > 0 && i % 3 == 0 {
+                writeln!(f, "───┼───┼───")?;
+            }
+            for j in 0..9 {
+                if j > 0 && j % 3 == 0 {
+                    write!(f, "│")?;
+                }
+                match self.board[i][j] {
+                    Some(Player::X) => write!(f, "X")?,
+                    Some(Player::O) => write!(f, "O")?,
+                    None => write!(f, "•")?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Game for MetaTac {
+    type Action = Action;
+
+    fn get_actions(&self) -> Vec<Self::Action> {
+        if self.winner.is_some() {
+            return Vec::new();
+        }
+
+        let mut result = Vec::with_capacity(81);
+        for x in 0..9 {
+            for y in 0..9 {
+                let index = sub_board_index((x, y));
+                let allowed = self.focus.is_none_or(|focus| focus == index);
+                if allowed && self.board[x][y].is_none() && !self.is_closed(index) {
+                    result.push((x, y));
+                }
+            }
+        }
+        result
+    }
+
+    fn apply_action(&mut self, action: Self::Action) {
+        self.play(self.get_player(), action);
+        self.current_player = !self.current_player;
+    }
+
+    fn get_victory_state(&self) -> VictoryState {
+        if let Some(winner) = self.winner {
+            VictoryState::Won(winner)
+        } else if self.get_actions().is_empty() {
+            VictoryState::Draw
+        } else {
+            VictoryState::InProgress
+        }
+    }
+
+    fn get_player(&self) -> Player {
+        self.current_player
+    }
+}
+
+/// The first 81 inputs encode the stones, the second 81 inputs mark the
+/// cells that are legal to play in.
+impl NetworkGame<162, 81> for MetaTac {
+    const NAME: &'static str = "meta_tac";
+
+    fn tensorize(&self) -> Tensor1D<162> {
+        let mut data = [0.0; 162];
+
+        for x in 0..9 {
+            for y in 0..9 {
+                let value = match self.board[x][y] {
+                    None => 0.0,
+                    Some(Player::X) => 1.0,
+                    Some(Player::O) => -1.0,
+                };
+                data[x + y * 9] = value;
+            }
+        }
+
+        for action in self.get_actions() {
+            data[81 + Self::action_to_index(action)] = 1.0;
+        }
+
+        Tensor1D::new(data)
+    }
+
+    fn action_to_index(action: Action) -> usize {
+        action.0 + action.1 * 9
+    }
+
+    fn index_to_action(index: usize) -> Action {
+        (index % 9, index / 9)
+    }
+
+    /// A symmetry of the 9x9 board maps sub-boards onto sub-boards and acts
+    /// the same way on the cells inside them, so the rules are preserved.
+    fn symmetries(&self) -> Vec<(Self, [usize; 81])> {
+        let map_sub_board = |symmetry: SquareSymmetry, index: usize| {
+            let (x, y) = symmetry.apply((index % 3, index / 3), 3);
+            x + 3 * y
+        };
+
+        SquareSymmetry::all()
+            .filter(|&symmetry| symmetry != SquareSymmetry::IDENTITY)
+            .map(|symmetry| {
+                let mut variant = self.clone();
+                let mut permutation = [0; 81];
+                for x in 0..9 {
+                    for y in 0..9 {
+                        let (sx, sy) = symmetry.apply((x, y), 9);
+                        variant.board[sx][sy] = self.board[x][y];
+                        permutation[Self::action_to_index((x, y))] =
+                            Self::action_to_index((sx, sy));
+                    }
+                }
+                for (index, sub_winner) in self.sub_winners.iter().enumerate() {
+                    variant.sub_winners[map_sub_board(symmetry, index)] = *sub_winner;
+                }
+                variant.focus = self.focus.map(|focus| map_sub_board(symmetry, focus));
+                (variant, permutation)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wins the sub-board with the given index for `player` with its
+    /// first row, without changing the player to move.
+    fn win_sub_board(state: &mut MetaTac, player: Player, index: usize) {
+        let (offset_x, offset_y) = (3 * (index % 3), 3 * (index / 3));
+        for x in 0..3 {
+            assert!(state.play(player, (offset_x + x, offset_y)));
+        }
+    }
+
+    #[test]
+    fn opponent_is_sent_to_the_matching_sub_board() {
+        let mut state = MetaTac::new();
+        // The centre sub-board, cell (1, 2) inside it.
+        state.apply_action((4, 5));
+
+        assert_eq!(state.focus, Some(7));
+        let actions = state.get_actions();
+        assert_eq!(actions.len(), 9);
+        assert!(actions.iter().all(|&action| sub_board_index(action) == 7));
+    }
+
+    #[test]
+    fn sent_to_a_closed_sub_board_plays_anywhere() {
+        let mut state = MetaTac::new();
+        win_sub_board(&mut state, Player::X, 0);
+        // Cell (0, 0) of the centre sub-board points to the won sub-board 0.
+        state.apply_action((3, 3));
+
+        assert_eq!(state.focus, None);
+        let actions = state.get_actions();
+        // Everything but the won sub-board and the played cell.
+        assert_eq!(actions.len(), 81 - 9 - 1);
+        assert!(actions.iter().all(|&action| sub_board_index(action) != 0));
+    }
+
+    #[test]
+    fn free_cells_of_a_won_sub_board_are_not_legal() {
+        let mut state = MetaTac::new();
+        win_sub_board(&mut state, Player::O, 4);
+
+        assert!(!state.play(Player::X, (4, 4)));
+        let actions = state.get_actions();
+        assert!(actions.iter().all(|&action| sub_board_index(action) != 4));
+    }
+
+    #[test]
+    fn three_sub_boards_in_a_row_win() {
+        let mut state = MetaTac::new();
+        win_sub_board(&mut state, Player::X, 0);
+        win_sub_board(&mut state, Player::O, 4);
+        win_sub_board(&mut state, Player::X, 3);
+        assert!(!state.get_victory_state().is_terminal());
+
+        win_sub_board(&mut state, Player::X, 6);
+        assert!(matches!(
+            state.get_victory_state(),
+            VictoryState::Won(Player::X)
+        ));
+        assert!(state.get_actions().is_empty());
+    }
+
+    #[test]
+    fn symmetries_map_legal_actions_focus_and_sub_boards() {
+        for state in crate::nn::tests::assert_symmetric_actions(MetaTac::new()) {
+            for (variant, _) in state.symmetries() {
+                assert_eq!(variant.focus.is_some(), state.focus.is_some());
+                for index in 0..9 {
+                    assert_eq!(
+                        variant.sub_winners[index],
+                        line_winner(variant.sub_board(index))
+                    );
+                }
+                assert_eq!(variant.winner, state.winner);
+            }
+        }
+    }
+}
@@ -1,101 +1,340 @@
+use std::{cell::RefCell, marker::PhantomData};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
 use dfdx::{
     prelude::{Linear, Module, ReLU, ResetParams, Softmax, SplitInto, Tanh},
-    tensor::{HasArrayData, Tensor1D, TensorCreator},
+    tensor::{HasArrayData, Tensor1D, Tensor2D, TensorCreator},
 };
 
 use crate::{
-    game::{Game, Player},
+    game::Game,
     mcts::{Edge, MctsConfigTrait, Node},
-    tictactoe::TicTacToe,
 };
 
-pub fn tensorize(state: &TicTacToe) -> Tensor1D<9> {
-    let mut data = [0.0; 9];
-
-    for i in 0..3 {
-        for j in 0..3 {
-            let value = match state.board[i][j] {
-                None => 0.0,
-                Some(Player::X) => 1.0,
-                Some(Player::O) => -1.0,
-            };
-            data[i + j * 3] = value;
-        }
-    }
+/// A game that can be fed into a neural network.
+///
+/// `I` is the size of the input tensor and `A` is the number of distinct
+/// actions, which is also the size of the policy output. Every action must
+/// map to a unique index in `0..A`.
+pub trait NetworkGame<const I: usize, const A: usize>: Game {
+    /// Identifies the game in model files.
+    const NAME: &'static str;
+
+    /// Encodes the state as the input of the network.
+    fn tensorize(&self) -> Tensor1D<I>;
+    fn action_to_index(action: Self::Action) -> usize;
+    fn index_to_action(index: usize) -> Self::Action;
 
-    Tensor1D::new(data)
+    /// Returns the symmetric variants of the state, not including the state
+    /// itself. Each comes with a permutation of the policy: the action with
+    /// index `i` in this state has index `permutation[i]` in the variant.
+    fn symmetries(&self) -> Vec<(Self, [usize; A])> {
+        Vec::new()
+    }
 }
 
-/// Neural Network based solution for TicTacToe
+/// Number of states that are evaluated in one forward pass of the network
+/// during a [Tree::batched_search](crate::mcts::Tree::batched_search).
+pub const EVALUATION_BATCH_SIZE: usize = 32;
+
+/// Neural Network based solution for any game implementing [NetworkGame].
 #[derive(Debug, Clone)]
-pub struct NetworkMctsConfig {
-    pub mlp: MultiLayerPerceptron,
+pub struct NetworkMctsConfig<G: NetworkGame<I, A>, const I: usize, const A: usize> {
+    pub mlp: MultiLayerPerceptron<I, A>,
     pub temperature: f32,
     pub power: usize,
-    pub batch_size: usize,
+    /// Minimal number of positions self-play generates per generation. Games
+    /// are always finished, so there may be a few more. Symmetric variants
+    /// from augmentation don't count towards this.
+    pub samples_per_generation: usize,
+    /// Concentration of the Dirichlet noise added to the root priors during
+    /// self-play.
+    pub dirichlet_alpha: f32,
+    /// Share of the Dirichlet noise in the root priors, zero disables it.
+    pub dirichlet_weight: f32,
+    /// Share of the search value in the value target of the training data.
+    /// The rest comes from the final outcome of the self-play game.
+    pub search_value_weight: f32,
+    /// Adds the symmetric variants of every self-play position to the
+    /// training data.
+    pub augment_symmetries: bool,
+    /// Evaluates a random symmetric variant of a state instead of the state
+    /// itself, which averages out asymmetries the network has learned.
+    pub random_symmetry_inference: bool,
+    /// Picks the variant for random symmetry inference. It sits in a
+    /// [RefCell], because [MctsConfigTrait] only hands out `&self`.
+    symmetry_rng: RefCell<StdRng>,
+    phantom_data: PhantomData<G>,
 }
 
-pub type MultiLayerPerceptron = (
-    Linear<9, 128>,
+pub type MultiLayerPerceptron<const I: usize, const A: usize> = (
+    Linear<I, 128>,
     ReLU,
     Linear<128, 128>,
     ReLU,
-    SplitInto<((Linear<128, 9>, Softmax), (Linear<128, 1>, Tanh))>,
+    SplitInto<((Linear<128, A>, Softmax), (Linear<128, 1>, Tanh))>,
 );
 
-impl NetworkMctsConfig {
-    pub fn new() -> NetworkMctsConfig {
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize, Serialize)]
+pub enum Activation {
+    ReLU,
+    Softmax,
+    Tanh,
+}
+
+/// Describes one linear layer of a network and the activation after it.
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct LayerDescription {
+    pub name: String,
+    pub input: usize,
+    pub output: usize,
+    pub activation: Activation,
+}
+
+/// The layers of a [MultiLayerPerceptron], in the order of its tuple.
+pub fn architecture<const I: usize, const A: usize>() -> Vec<LayerDescription> {
+    let layer = |name: &str, input, output, activation| LayerDescription {
+        name: name.to_string(),
+        input,
+        output,
+        activation,
+    };
+
+    vec![
+        layer("hidden1", I, 128, Activation::ReLU),
+        layer("hidden2", 128, 128, Activation::ReLU),
+        layer("policy", 128, A, Activation::Softmax),
+        layer("value", 128, 1, Activation::Tanh),
+    ]
+}
+
+impl<G: NetworkGame<I, A>, const I: usize, const A: usize> NetworkMctsConfig<G, I, A> {
+    pub fn new() -> Self {
         let mut rng = rand::thread_rng();
 
-        let mut mlp: MultiLayerPerceptron = Default::default();
+        let mut mlp: MultiLayerPerceptron<I, A> = Default::default();
         mlp.reset_params(&mut rng);
 
+        Self::from_mlp(mlp)
+    }
+    pub fn from_mlp(mlp: MultiLayerPerceptron<I, A>) -> Self {
         NetworkMctsConfig {
             mlp,
             temperature: 1.0,
             power: 10000,
-            batch_size: 100,
+            samples_per_generation: 100,
+            dirichlet_alpha: 0.3,
+            dirichlet_weight: 0.25,
+            search_value_weight: 0.0,
+            augment_symmetries: false,
+            random_symmetry_inference: false,
+            symmetry_rng: RefCell::new(StdRng::from_entropy()),
+            phantom_data: PhantomData,
         }
     }
-    pub fn with_power(self, power: usize) -> NetworkMctsConfig {
+    pub fn with_power(self, power: usize) -> Self {
         NetworkMctsConfig { power, ..self }
     }
-    pub fn with_temperature(self, temperature: f32) -> NetworkMctsConfig {
+    pub fn with_temperature(self, temperature: f32) -> Self {
         NetworkMctsConfig {
             temperature,
             ..self
         }
     }
-    pub fn with_batch_size(self, batch_size: usize) -> NetworkMctsConfig {
-        NetworkMctsConfig { batch_size, ..self }
+    pub fn with_samples_per_generation(self, samples_per_generation: usize) -> Self {
+        NetworkMctsConfig {
+            samples_per_generation,
+            ..self
+        }
+    }
+    pub fn with_dirichlet_noise(self, alpha: f32, weight: f32) -> Self {
+        NetworkMctsConfig {
+            dirichlet_alpha: alpha,
+            dirichlet_weight: weight,
+            ..self
+        }
+    }
+    pub fn with_search_value_weight(self, search_value_weight: f32) -> Self {
+        NetworkMctsConfig {
+            search_value_weight,
+            ..self
+        }
+    }
+    pub fn with_augment_symmetries(self, augment_symmetries: bool) -> Self {
+        NetworkMctsConfig {
+            augment_symmetries,
+            ..self
+        }
+    }
+    pub fn with_random_symmetry_inference(self, random_symmetry_inference: bool) -> Self {
+        NetworkMctsConfig {
+            random_symmetry_inference,
+            ..self
+        }
+    }
+    /// Makes the choices of random symmetry inference reproducible.
+    pub fn with_seed(self, seed: u64) -> Self {
+        self.reseed(seed);
+        self
+    }
+
+    /// Like [NetworkMctsConfig::with_seed], for a config that is in use.
+    pub fn reseed(&self, seed: u64) {
+        *self.symmetry_rng.borrow_mut() = StdRng::seed_from_u64(seed);
     }
 }
 
-impl MctsConfigTrait<TicTacToe> for NetworkMctsConfig {
-    fn node_for_new_state(&self, state: TicTacToe) -> (crate::mcts::Node<TicTacToe>, f32) {
-        let input = tensorize(&state);
+impl<G: NetworkGame<I, A>, const I: usize, const A: usize> Default for NetworkMctsConfig<G, I, A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        let (policy, value_output) = self.mlp.forward(input);
+impl<G: NetworkGame<I, A>, const I: usize, const A: usize> NetworkMctsConfig<G, I, A> {
+    /// Evaluates up to `B` states in one forward pass. Unused rows of the
+    /// batch are left at zero and their output is ignored.
+    fn evaluate_batch<const B: usize>(&self, states: Vec<G>) -> Vec<(Node<G>, f32)> {
+        assert!(states.len() <= B, "Too many states for one batch");
 
-        let actions = state.get_actions();
+        let mut input: Tensor2D<B, I> = Tensor2D::zeros();
+        let mut permutations = Vec::with_capacity(states.len());
+        for (row, state) in input.mut_data().iter_mut().zip(states.iter()) {
+            let (state_input, permutation) = self.network_input(state);
+            row.copy_from_slice(state_input.data());
+            permutations.push(permutation);
+        }
 
-        let children = actions
-            .iter()
-            .map(|action| {
-                Edge::new(
-                    state.clone(),
-                    *action,
-                    policy.data()[action.0 + 3 * action.1],
-                )
+        let (policy, value_output) = self.mlp.forward(input);
+
+        states
+            .into_iter()
+            .zip(permutations)
+            .enumerate()
+            .map(|(i, (state, permutation))| {
+                let node = node_from_policy(state, &policy.data()[i], permutation);
+                (node, value_output.data()[i][0])
             })
-            .collect();
+            .collect()
+    }
+
+    /// Returns the network input for a state. With random symmetry inference,
+    /// this may be the input of a symmetric variant, in which case the policy
+    /// permutation of that variant is returned as well.
+    fn network_input(&self, state: &G) -> (Tensor1D<I>, Option<[usize; A]>) {
+        if self.random_symmetry_inference {
+            let mut symmetries = state.symmetries();
+            // The state itself is one of the choices as well.
+            let choice = self
+                .symmetry_rng
+                .borrow_mut()
+                .gen_range(0..=symmetries.len());
+            if choice < symmetries.len() {
+                let (variant, permutation) = symmetries.swap_remove(choice);
+                return (variant.tensorize(), Some(permutation));
+            }
+        }
+        (state.tensorize(), None)
+    }
+}
 
-        let node = Node {
-            state,
-            visit_count: 0.0,
-            children,
-        };
+/// If the policy belongs to a symmetric variant of the state, `permutation`
+/// maps the action indices of the state to the ones of the variant.
+fn node_from_policy<G: NetworkGame<I, A>, const I: usize, const A: usize>(
+    state: G,
+    policy: &[f32; A],
+    permutation: Option<[usize; A]>,
+) -> Node<G> {
+    let actions = state.get_actions();
+
+    let children = actions
+        .iter()
+        .map(|action| {
+            let index = G::action_to_index(*action);
+            let index = permutation.map_or(index, |permutation| permutation[index]);
+            Edge::new(*action, policy[index])
+        })
+        .collect();
+
+    Node::new(state, children)
+}
+
+impl<G: NetworkGame<I, A>, const I: usize, const A: usize> MctsConfigTrait<G>
+    for NetworkMctsConfig<G, I, A>
+{
+    fn node_for_new_state(&self, state: G) -> (Node<G>, f32) {
+        let (input, permutation) = self.network_input(&state);
+
+        let (policy, value_output) = self.mlp.forward(input);
+
+        let node = node_from_policy(state, policy.data(), permutation);
 
         (node, value_output.data()[0])
     }
+
+    fn nodes_for_new_states(&self, mut states: Vec<G>) -> Vec<(Node<G>, f32)> {
+        let mut result = Vec::with_capacity(states.len());
+        while !states.is_empty() {
+            let rest = states.split_off(EVALUATION_BATCH_SIZE.min(states.len()));
+            result.extend(self.evaluate_batch::<EVALUATION_BATCH_SIZE>(states));
+            states = rest;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use rand::seq::SliceRandom;
+
+    use super::*;
+
+    /// Checks the symmetries of positions along random games: the
+    /// permutation of every variant must map the legal actions of the state
+    /// exactly onto the legal actions of the variant. Returns the checked
+    /// positions.
+    pub(crate) fn assert_symmetric_actions<G: NetworkGame<I, A>, const I: usize, const A: usize>(
+        initial_state: G,
+    ) -> Vec<G> {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut positions = Vec::new();
+        for _ in 0..20 {
+            let mut state = initial_state.clone();
+            loop {
+                positions.push(state.clone());
+                let actions = state.get_actions();
+                let Some(&action) = actions.choose(&mut rng) else {
+                    break;
+                };
+                state.apply_action(action);
+            }
+        }
+
+        for state in &positions {
+            let mut actions: Vec<usize> = state
+                .get_actions()
+                .into_iter()
+                .map(G::action_to_index)
+                .collect();
+            for (variant, permutation) in state.symmetries() {
+                let mut mapped: Vec<usize> = actions.iter().map(|&i| permutation[i]).collect();
+                let mut expected: Vec<usize> = variant
+                    .get_actions()
+                    .into_iter()
+                    .map(G::action_to_index)
+                    .collect();
+                mapped.sort_unstable();
+                expected.sort_unstable();
+                assert_eq!(
+                    mapped, expected,
+                    "for the variant\n{}of\n{}",
+                    variant, state
+                );
+            }
+            actions.sort_unstable();
+            assert!(actions.windows(2).all(|pair| pair[0] != pair[1]));
+        }
+        positions
+    }
 }
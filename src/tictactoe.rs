@@ -1,10 +1,15 @@
 use std::fmt::Display;
 
-use crate::game::{Game, Player};
+use dfdx::tensor::{Tensor1D, TensorCreator};
+
+use crate::{
+    game::{Game, Player, SquareSymmetry},
+    nn::NetworkGame,
+};
 
 /// Implements a simple TicTacToe game.
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub struct TicTacToe {
     pub board: [[Option<Player>; 3]; 3],
     pub current_player: Player,
@@ -12,13 +17,6 @@ pub struct TicTacToe {
 
 type Action = (usize, usize);
 
-pub fn action_to_index(action: Action) -> usize {
-    action.0 + action.1 * 3
-}
-pub fn index_to_action(index: usize) -> Action {
-    (index % 3, index / 3)
-}
-
 impl TicTacToe {
     pub fn new() -> TicTacToe {
         TicTacToe {
@@ -41,26 +39,32 @@ impl TicTacToe {
 
         for i in 0..3 {
             if self.board[i][0] == self.board[i][1] && self.board[i][1] == self.board[i][2] {
-                winner = self.board[i][0];
+                winner = winner.or(self.board[i][0]);
             }
 
             if self.board[0][i] == self.board[1][i] && self.board[1][i] == self.board[2][i] {
-                winner = self.board[0][i];
+                winner = winner.or(self.board[0][i]);
             }
         }
 
         if self.board[0][0] == self.board[1][1] && self.board[1][1] == self.board[2][2] {
-            winner = self.board[0][0];
+            winner = winner.or(self.board[0][0]);
         }
 
         if self.board[0][2] == self.board[1][1] && self.board[1][1] == self.board[2][0] {
-            winner = self.board[0][2];
+            winner = winner.or(self.board[0][2]);
         }
 
         winner
     }
 }
 
+impl Default for TicTacToe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Display for TicTacToe {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for i in 0..3 {
@@ -81,6 +85,10 @@ impl Game for TicTacToe {
     type Action = Action;
 
     fn get_actions(&self) -> Vec<Self::Action> {
+        if self.winner().is_some() {
+            return Vec::new();
+        }
+
         let mut result = Vec::with_capacity(9);
         for x in 0..3 {
             for y in 0..3 {
@@ -111,3 +119,74 @@ impl Game for TicTacToe {
         self.current_player
     }
 }
+
+impl NetworkGame<9, 9> for TicTacToe {
+    const NAME: &'static str = "tictactoe";
+
+    fn tensorize(&self) -> Tensor1D<9> {
+        let mut data = [0.0; 9];
+
+        for i in 0..3 {
+            for j in 0..3 {
+                let value = match self.board[i][j] {
+                    None => 0.0,
+                    Some(Player::X) => 1.0,
+                    Some(Player::O) => -1.0,
+                };
+                data[i + j * 3] = value;
+            }
+        }
+
+        Tensor1D::new(data)
+    }
+
+    fn action_to_index(action: Action) -> usize {
+        action.0 + action.1 * 3
+    }
+
+    fn index_to_action(index: usize) -> Action {
+        (index % 3, index / 3)
+    }
+
+    fn symmetries(&self) -> Vec<(Self, [usize; 9])> {
+        SquareSymmetry::all()
+            .filter(|&symmetry| symmetry != SquareSymmetry::IDENTITY)
+            .map(|symmetry| {
+                let mut variant = self.clone();
+                let mut permutation = [0; 9];
+                for x in 0..3 {
+                    for y in 0..3 {
+                        let (sx, sy) = symmetry.apply((x, y), 3);
+                        variant.board[sx][sy] = self.board[x][y];
+                        permutation[Self::action_to_index((x, y))] =
+                            Self::action_to_index((sx, sy));
+                    }
+                }
+                (variant, permutation)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn win_is_kept_when_a_later_line_is_empty() {
+        let mut state = TicTacToe::new();
+        state.board[0] = [Some(Player::X); 3];
+        state.board[1] = [Some(Player::O), Some(Player::O), None];
+        state.current_player = Player::O;
+
+        assert_eq!(state.winner(), Some(Player::X));
+        assert!(state.get_actions().is_empty());
+        for (variant, _) in state.symmetries() {
+            assert_eq!(variant.winner(), Some(Player::X), "in\n{}", variant);
+        }
+    }
+    #[test]
+    fn symmetries_map_legal_actions() {
+        crate::nn::tests::assert_symmetric_actions(TicTacToe::new());
+    }
+}
@@ -0,0 +1,275 @@
+//! Monte Carlo tree search on a graph of positions instead of a tree.
+//!
+//! In a [Tree](crate::mcts::Tree), a position that can be reached by
+//! different move orders is searched and evaluated once for every order.
+//! The [SearchGraph] stores every position only once, keyed by
+//! [StateHash::state_hash], and shares its statistics between all parents.
+
+use std::collections::HashMap;
+
+use crate::{
+    game::{Game, StateHash},
+    mcts::{score_terminal_victory_state, MctsConfigTrait},
+};
+
+/// A position in a [SearchGraph].
+pub struct GraphNode<G: Game> {
+    pub state: G,
+    /// Number of walks through this position, from any parent. The
+    /// evaluation of the position itself counts as the first one.
+    pub visit_count: f32,
+    /// Sum of the values of those walks, from the perspective of the player
+    /// to move.
+    pub total_value: f32,
+    pub children: Vec<GraphEdge<G>>,
+}
+
+pub struct GraphEdge<G: Game> {
+    pub action: G::Action,
+    /// Index of the position behind this edge, once it has been reached.
+    pub node: Option<usize>,
+    /// Number of walks along this edge. Unlike in a tree, this can be lower
+    /// than the visit count of the position behind it.
+    pub visit_count: f32,
+    pub prior_probability: f32,
+}
+
+/// Implements transposition-aware monte carlo tree search.
+///
+/// This assumes that a position can never be repeated in a game, so the
+/// graph has no cycles. All games in this crate only ever add stones.
+pub struct SearchGraph<G: Game> {
+    nodes: Vec<GraphNode<G>>,
+    /// Maps a [StateHash::state_hash] to the indices of all positions with
+    /// that hash. There is more than one only if different positions
+    /// collide.
+    index: HashMap<u64, Vec<usize>>,
+    root: usize,
+}
+
+impl<G: Game + StateHash + PartialEq> SearchGraph<G> {
+    pub fn new(state: G, config: &impl MctsConfigTrait<G>) -> Self {
+        let mut graph = SearchGraph {
+            nodes: Vec::new(),
+            index: HashMap::new(),
+            root: 0,
+        };
+        graph.root = graph.insert(state, config).0;
+        graph
+    }
+
+    pub fn root(&self) -> &GraphNode<G> {
+        &self.nodes[self.root]
+    }
+
+    pub fn node(&self, index: usize) -> &GraphNode<G> {
+        &self.nodes[index]
+    }
+
+    /// Number of distinct positions in the graph.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Mean value of the position behind the edge, from the perspective of
+    /// the player who takes the action. Zero if it was never reached.
+    pub fn expected_reward(&self, edge: &GraphEdge<G>) -> f32 {
+        match edge.node {
+            // Here we assume alternating players.
+            Some(index) => -self.nodes[index].total_value / self.nodes[index].visit_count,
+            None => 0.0,
+        }
+    }
+
+    /// Evaluates a new position and adds it to the graph. Returns its index
+    /// and value.
+    fn insert(&mut self, state: G, config: &impl MctsConfigTrait<G>) -> (usize, f32) {
+        let (node, value) = config.node_for_new_state(state);
+        let children = node
+            .children
+            .into_iter()
+            .map(|edge| GraphEdge {
+                action: edge.action,
+                node: None,
+                visit_count: 0.0,
+                prior_probability: edge.prior_probability,
+            })
+            .collect();
+
+        let index = self.nodes.len();
+        self.index
+            .entry(node.state.state_hash())
+            .or_default()
+            .push(index);
+        self.nodes.push(GraphNode {
+            state: node.state,
+            visit_count: 1.0,
+            total_value: value,
+            children,
+        });
+
+        (index, value)
+    }
+
+    /// The index of the position, if it is already in the graph.
+    fn find(&self, state: &G) -> Option<usize> {
+        self.index
+            .get(&state.state_hash())?
+            .iter()
+            .copied()
+            .find(|&index| self.nodes[index].state == *state)
+    }
+
+    /// Choose an action that maximizes Q+U, like a [Tree](crate::mcts::Tree)
+    /// does. The parent visits are the walks that went through this node's
+    /// edges, so visits from other parents don't change its exploration.
+    fn choose_edge_index(&self, node: &GraphNode<G>) -> usize {
+        let parent_visits: f32 = node.children.iter().map(|edge| edge.visit_count).sum();
+
+        let mut best_action_index = 0;
+        let mut best_action_value = f32::NEG_INFINITY;
+
+        for (i, edge) in node.children.iter().enumerate() {
+            let explore_value = node.state.exploration_factor()
+                * edge.prior_probability
+                * (parent_visits.sqrt() / (1.0 + edge.visit_count) + 0.0001);
+            let action_value = self.expected_reward(edge) + explore_value;
+            if action_value > best_action_value {
+                best_action_index = i;
+                best_action_value = action_value;
+            }
+        }
+
+        best_action_index
+    }
+
+    /// Descends from the root until it reaches a new position or a terminal
+    /// one, and propagates the value back up along the way it came. Returns
+    /// the value from the perspective of the player at the root.
+    pub fn walk_to_leaf(&mut self, config: &impl MctsConfigTrait<G>) -> f32 {
+        // The (node, edge) pairs on the way down.
+        let mut path: Vec<(usize, usize)> = Vec::new();
+        let mut current = self.root;
+
+        let leaf_value = loop {
+            let node = &self.nodes[current];
+            if node.children.is_empty() {
+                let value = score_terminal_victory_state(&node.state, node.state.get_player());
+                let node = &mut self.nodes[current];
+                node.visit_count += 1.0;
+                node.total_value += value;
+                break value;
+            }
+
+            let edge_index = self.choose_edge_index(node);
+            path.push((current, edge_index));
+
+            let edge = &node.children[edge_index];
+            if let Some(child) = edge.node {
+                current = child;
+                continue;
+            }
+
+            let mut new_state = node.state.clone();
+            new_state.apply_action(edge.action);
+            match self.find(&new_state) {
+                // A transposition: the position was already reached by
+                // another move order, so we continue from there.
+                Some(child) => {
+                    self.nodes[current].children[edge_index].node = Some(child);
+                    current = child;
+                }
+                // The evaluation of a new position is counted on insert.
+                None => {
+                    let (child, value) = self.insert(new_state, config);
+                    self.nodes[current].children[edge_index].node = Some(child);
+                    break value;
+                }
+            }
+        };
+
+        let mut value = leaf_value;
+        for &(node_index, edge_index) in path.iter().rev() {
+            // Here we assume alternating players.
+            value = -value;
+            let node = &mut self.nodes[node_index];
+            node.children[edge_index].visit_count += 1.0;
+            node.visit_count += 1.0;
+            node.total_value += value;
+        }
+
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::{self, Display, Formatter};
+
+    use super::*;
+    use crate::{
+        game::{Player, VictoryState},
+        mcts::RolloutMctsConfig,
+        tictactoe::TicTacToe,
+    };
+
+    /// TicTacToe where all positions with the same number of actions have
+    /// the same hash. It doesn't implement [Hash](std::hash::Hash).
+    #[derive(Debug, Clone, PartialEq)]
+    struct Colliding(TicTacToe);
+
+    impl Display for Colliding {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            self.0.fmt(f)
+        }
+    }
+
+    impl Game for Colliding {
+        type Action = <TicTacToe as Game>::Action;
+
+        fn get_actions(&self) -> Vec<Self::Action> {
+            self.0.get_actions()
+        }
+        fn apply_action(&mut self, action: Self::Action) {
+            self.0.apply_action(action)
+        }
+        fn get_victory_state(&self) -> VictoryState {
+            self.0.get_victory_state()
+        }
+        fn get_player(&self) -> Player {
+            self.0.get_player()
+        }
+    }
+
+    impl StateHash for Colliding {
+        fn state_hash(&self) -> u64 {
+            self.get_actions().len() as u64
+        }
+    }
+
+    #[test]
+    fn colliding_positions_stay_apart() {
+        let config: RolloutMctsConfig<Colliding> = Default::default();
+        let mut graph = SearchGraph::new(Colliding(TicTacToe::new()), &config);
+        for _ in 0..1000 {
+            graph.walk_to_leaf(&config);
+        }
+
+        for (i, node) in graph.nodes.iter().enumerate() {
+            assert!(graph.nodes[..i]
+                .iter()
+                .all(|other| other.state != node.state));
+            for edge in &node.children {
+                if let Some(child) = edge.node {
+                    let mut state = node.state.clone();
+                    state.apply_action(edge.action);
+                    assert!(graph.nodes[child].state == state);
+                }
+            }
+        }
+    }
+}